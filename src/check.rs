@@ -0,0 +1,148 @@
+//! Check songs for problems, without rendering them.
+use crate::diagnostics::{Code, Diagnostics};
+use crate::parser::{ChordFileExpression, Token};
+use crate::render::{RenderOptions, SongState};
+use crate::song::Song;
+use std::io;
+use std::path::Path;
+
+/// Check a chopro source for problems, without rendering it.
+///
+/// Returns the number of problems found.
+pub fn check_source<R: io::Read>(
+    source: R,
+    songfilename: &str,
+    options: &RenderOptions,
+) -> io::Result<usize> {
+    let diagnostics = Diagnostics::new(songfilename);
+    let path = Path::new(songfilename);
+    for mut song in Song::parse(source, path, &diagnostics, &options.selectors)
+    {
+        song.transpose(options.transpose);
+        check_song(song, options, &diagnostics)?;
+    }
+    Ok(diagnostics.n_warnings() + diagnostics.n_errors())
+}
+
+/// Check a song for problems, reporting them to `diagnostics`.
+pub fn check_song(
+    song: Song,
+    options: &RenderOptions,
+    diagnostics: &Diagnostics,
+) -> io::Result<()> {
+    if song.title.is_none() {
+        diagnostics.warning(song.pos, Code::NoTitle, "Song has no title");
+    }
+    let mut state = SongState::new(options, diagnostics);
+    state.set_capo(song.pos, &song.meta);
+    for def in song.chord_defs {
+        state.define(def.pos, def.name, def.def, def.fingers);
+    }
+    for token in song.body {
+        check_token(token, &mut state, false)?;
+    }
+    state.report_unknown_chords();
+    Ok(())
+}
+
+#[test]
+fn test_check_song() {
+    let options = RenderOptions {
+        selectors: vec!["guitar".into()],
+        ..Default::default()
+    };
+    let check =
+        |song: &str| check_source(song.as_bytes(), "test", &options).unwrap();
+    assert_eq!(
+        check("{title: Test}\n[G]Hello [Smaj9]world\n{eoc}\n{sot}\n"),
+        3
+    );
+    assert_eq!(check("{title: Test}\n{soc}\n[G]Hello [C]world\n{eoc}\n"), 0);
+    assert_eq!(
+        check(
+            "{title: Test}\n\
+             {define-mandolin: Q base-fret 0 frets 1 2 3 4}\n\
+             {soc-ukulele}\n[Smaj9]la\n{eoc-ukulele}\n\
+             {define-!mandolin: Q base-fret 0 frets 1 2 3 4}\n",
+        ),
+        1,
+    );
+    assert_eq!(
+        check("{title: Test}\n{chorus}\n{soc}\nla\n{eoc}\n{chorus}\n"),
+        1
+    );
+    assert_eq!(check("{title: Test}\n{capo: 2}\n[G]la\n"), 0);
+    assert_eq!(check("{title: Test}\n{capo: second}\n[G]la\n"), 1);
+    assert_eq!(
+        check(
+            "{title: Test}\n{define: A9 base-fret 10 frets N 12 11 12 12 x}\n"
+        ),
+        0
+    );
+    assert_eq!(check("{title: Test}\n{define: Q frets 1 7 0 0 0 0}\n"), 1);
+    assert_eq!(check("{title: Test}\n{soc}\n{columns: 2}\n{eoc}\n"), 1);
+}
+
+#[test]
+fn test_check_include() {
+    let dir = std::env::temp_dir()
+        .join(format!("chord3-test-include-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("chorus.chopro"), "{soc}\n[Smaj9]la\n{eoc}\n")
+        .unwrap();
+    std::fs::write(dir.join("cycle.chopro"), "{include: cycle.chopro}\n")
+        .unwrap();
+    let song = dir.join("song.chopro");
+    std::fs::write(
+        &song,
+        "{title: Test}\n{include: chorus.chopro}\n{include: cycle.chopro}\n",
+    )
+    .unwrap();
+    let song = song.to_str().unwrap();
+    let options = RenderOptions::default();
+    let n = check_source(std::fs::File::open(song).unwrap(), song, &options)
+        .unwrap();
+    assert_eq!(n, 2); // Smaj9 is unknown, and cycle.chopro is cyclic
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Check a token, that is `nested` in a chorus or section or not.
+fn check_token(
+    token: Token,
+    song: &mut SongState,
+    nested: bool,
+) -> io::Result<()> {
+    let Token { pos, expr, .. } = token;
+    match expr {
+        ChordFileExpression::Chorus { label, lines } => {
+            song.last_chorus = Some((label, lines.clone()));
+            for line in lines {
+                check_token(line, song, true)?;
+            }
+        }
+        ChordFileExpression::ChorusRecall { .. } => {
+            song.recall_chorus(pos);
+        }
+        ChordFileExpression::Section { lines, .. } => {
+            for line in lines {
+                check_token(line, song, true)?;
+            }
+        }
+        ChordFileExpression::EndOfChorus => song.stray_end(pos, "chorus"),
+        ChordFileExpression::EndOfSection { kind } => {
+            song.stray_end(pos, kind.name())
+        }
+        ChordFileExpression::EndOfTab => song.stray_end(pos, "tab"),
+        ChordFileExpression::Form { keys, form, .. } => {
+            song.use_form_chords(pos, &keys, &form)?
+        }
+        ChordFileExpression::Line { segments } => {
+            song.use_line_chords(pos, &segments)
+        }
+        ChordFileExpression::StartColumns { .. } if nested => {
+            song.misplaced_columns(pos)
+        }
+        _ => (),
+    }
+    Ok(())
+}
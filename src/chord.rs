@@ -0,0 +1,554 @@
+//! Chord names, parsed into their parts.
+use std::fmt;
+use std::str::FromStr;
+
+/// A chord, such as `Am7`, `F#m7b5` or `C/G`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chord {
+    pub root: Note,
+    pub quality: Quality,
+    /// Added notes, such as `7`, `maj7`, `9`, `add9` or `6/9`.
+    pub extensions: Vec<String>,
+    /// Altered notes, such as `b5` or `#9`.
+    pub alterations: Vec<String>,
+    /// A bass note other than the root, as in `C/G`.
+    pub bass: Option<Note>,
+}
+
+/// A note name, a letter and an accidental.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Note {
+    /// The letter, `A` to `G` (`H` is parsed as `B`).
+    pub letter: char,
+    pub accidental: Accidental,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Accidental {
+    Natural,
+    Sharp,
+    Flat,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quality {
+    Major,
+    Minor,
+    Diminished,
+    Augmented,
+    Suspended2,
+    Suspended4,
+}
+
+/// An error for a name that is not a chord.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChordError {
+    reason: String,
+}
+
+impl fmt::Display for ChordError {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        out.write_str(&self.reason)
+    }
+}
+
+impl std::error::Error for ChordError {}
+
+impl Chord {
+    /// The chord transposed by `semitones`.
+    ///
+    /// If the transposed root has an accidental, the bass is spelled
+    /// with the same kind of accidental, e.g. `C#/G#` rather than
+    /// `C#/Ab`.
+    pub fn transpose(&self, semitones: i8) -> Chord {
+        let root = self.root.transpose(semitones);
+        let bass = self.bass.map(|bass| match root.accidental {
+            Accidental::Natural => bass.transpose(semitones),
+            accidental => bass.transpose_as(semitones, accidental),
+        });
+        Chord {
+            root,
+            bass,
+            ..self.clone()
+        }
+    }
+
+    /// The part of the name after the root and quality, i.e. the
+    /// extensions, suspension and alterations.
+    pub fn suffix(&self) -> String {
+        let mut suffix = self.extensions.concat();
+        match self.quality {
+            Quality::Suspended2 => suffix.push_str("sus2"),
+            Quality::Suspended4 => suffix.push_str("sus4"),
+            _ => (),
+        }
+        suffix.push_str(&self.alterations.concat());
+        suffix
+    }
+
+    /// Names of this chord, with all possible spellings of the root
+    /// and bass (e.g. `C#/G#` is also `Db/G#`, `C#/Ab` and `Db/Ab`).
+    ///
+    /// The first name is the canonical name of this chord.
+    pub fn spellings(&self) -> Vec<String> {
+        let mut names = vec![self.to_string()];
+        let basses = match self.bass {
+            Some(bass) => bass.spellings().into_iter().map(Some).collect(),
+            None => vec![None],
+        };
+        for root in self.root.spellings() {
+            for bass in &basses {
+                let name = Chord {
+                    root,
+                    bass: *bass,
+                    ..self.clone()
+                }
+                .to_string();
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        names
+    }
+
+    /// The tones of this chord, as semitones above the root, each
+    /// with a flag telling if it is essential.
+    ///
+    /// Non-essential tones (such as the fifth of most chords, or the
+    /// ninth of an eleventh chord) may be left out when playing it.
+    pub fn intervals(&self) -> Vec<(i8, bool)> {
+        let mut third = match self.quality {
+            Quality::Major | Quality::Augmented => Some(4),
+            Quality::Minor | Quality::Diminished => Some(3),
+            Quality::Suspended2 => Some(2),
+            Quality::Suspended4 => Some(5),
+        };
+        let mut fifth = match self.quality {
+            Quality::Diminished => (6, true),
+            Quality::Augmented => (8, true),
+            _ => (7, false),
+        };
+        let seventh = match self.quality {
+            Quality::Diminished => 9,
+            _ => 10,
+        };
+        let mut tones = vec![];
+        for ext in &self.extensions {
+            let added: &[(i8, bool)] = match ext.as_str() {
+                "5" => {
+                    third = None;
+                    fifth.1 = true;
+                    &[]
+                }
+                "2" | "add2" | "add9" => &[(2, true)],
+                "4" | "add4" | "add11" => &[(5, true)],
+                "6" | "add6" | "add13" => &[(9, true)],
+                "6/9" => &[(9, true), (2, true)],
+                "7" => &[(seventh, true)],
+                "9" => &[(10, true), (2, true)],
+                "11" => &[(10, true), (2, false), (5, true)],
+                "13" => &[(10, true), (2, false), (9, true)],
+                "maj7" => &[(11, true)],
+                "maj9" => &[(11, true), (2, true)],
+                "maj11" => &[(11, true), (2, false), (5, true)],
+                "maj13" => &[(11, true), (2, false), (9, true)],
+                _ => &[],
+            };
+            tones.extend_from_slice(added);
+        }
+        for alt in &self.alterations {
+            let (replaces, tone) = match alt.as_str() {
+                "b5" => (7, 6),
+                "#5" => (7, 8),
+                "b9" => (2, 1),
+                "#9" => (2, 3),
+                "#11" => (5, 6),
+                "b13" => (9, 8),
+                _ => {
+                    // "alt", a dominant with (at least) a flat ninth.
+                    tones.extend_from_slice(&[(10, true), (1, true)]);
+                    continue;
+                }
+            };
+            if replaces == 7 {
+                fifth = (tone, true);
+            } else {
+                tones.retain(|(t, _)| *t != replaces);
+                tones.push((tone, true));
+            }
+        }
+        let mut result = vec![(0, true)];
+        result.extend(third.map(|third| (third, true)));
+        result.push(fifth);
+        for (tone, essential) in tones {
+            match result.iter_mut().find(|(t, _)| *t == tone) {
+                Some((_, e)) => *e |= essential,
+                None => result.push((tone, essential)),
+            }
+        }
+        result
+    }
+}
+
+impl FromStr for Chord {
+    type Err = ChordError;
+
+    fn from_str(name: &str) -> Result<Chord, ChordError> {
+        let err = |reason: String| ChordError {
+            reason: format!("{name:?} is not a chord, {reason}"),
+        };
+        let (root, rest) = Note::parse_prefix(name)
+            .ok_or_else(|| err("it does not start with a note".into()))?;
+        let (mut s, bass) = match rest.rsplit_once('/') {
+            Some((before, bass)) => match bass.parse::<Note>() {
+                Ok(bass) => (before, Some(bass)),
+                Err(_) => (rest, None),
+            },
+            None => (rest, None),
+        };
+        let mut chord = Chord {
+            root,
+            quality: Quality::Major,
+            extensions: vec![],
+            alterations: vec![],
+            bass,
+        };
+        if s.starts_with("maj") || s.starts_with("Maj") {
+            // A major seventh (or ninth, ...), handled below.
+        } else if let Some(rest) = strip_any(s, &["min", "m", "-"]) {
+            chord.quality = Quality::Minor;
+            s = rest;
+        } else if let Some(rest) = strip_any(s, &["dim", "°", "o"]) {
+            chord.quality = Quality::Diminished;
+            s = rest;
+        } else if let Some(rest) = strip_any(s, &["aug", "+"]) {
+            chord.quality = Quality::Augmented;
+            s = rest;
+        } else if let Some(rest) = s.strip_prefix('ø') {
+            chord.quality = Quality::Minor;
+            chord.extensions.push("7".into());
+            chord.alterations.push("b5".into());
+            s = rest.strip_prefix('7').unwrap_or(rest);
+        }
+        loop {
+            s = s.trim_start_matches(['(', ')', ',', ' ']);
+            if s.is_empty() {
+                break;
+            }
+            if let Some(rest) = strip_any(s, &["maj", "Maj", "M"]) {
+                // A plain `maj` is just a major triad.
+                let (n, rest) = split_number(rest);
+                match n {
+                    "" => (),
+                    "7" | "9" | "11" | "13" => {
+                        chord.extensions.push(format!("maj{n}"))
+                    }
+                    _ => return Err(err(format!("bad major {n:?}"))),
+                }
+                s = rest;
+            } else if let Some(rest) = strip_any(s, &["Δ", "^"]) {
+                let (n, rest) = split_number(rest);
+                let n = match n {
+                    "" => "7",
+                    "7" | "9" | "11" | "13" => n,
+                    _ => return Err(err(format!("bad major {n:?}"))),
+                };
+                chord.extensions.push(format!("maj{n}"));
+                s = rest;
+            } else if let Some(rest) = s.strip_prefix("add") {
+                let (n, rest) = split_number(rest);
+                if n.is_empty() {
+                    return Err(err("add without a number".into()));
+                }
+                chord.extensions.push(format!("add{n}"));
+                s = rest;
+            } else if let Some(rest) = s.strip_prefix("sus") {
+                let (n, rest) = split_number(rest);
+                chord.quality = match (chord.quality, n) {
+                    (Quality::Major, "2") => Quality::Suspended2,
+                    (Quality::Major, "4" | "") => Quality::Suspended4,
+                    _ => return Err(err(format!("bad sus{n}"))),
+                };
+                s = rest;
+            } else if let Some(rest) = s.strip_prefix("alt") {
+                chord.alterations.push("alt".into());
+                s = rest;
+            } else if let Some((flat, rest)) = strip_accidental(s) {
+                let (n, rest) = split_number(rest);
+                if !matches!(n, "5" | "9" | "11" | "13") {
+                    return Err(err(format!("bad alteration in {s:?}")));
+                }
+                let accidental = if flat { 'b' } else { '#' };
+                chord.alterations.push(format!("{accidental}{n}"));
+                s = rest;
+            } else {
+                let (n, rest) = split_number(s);
+                let (n, rest) = match (n, rest.strip_prefix("/9")) {
+                    ("6", Some(rest)) | ("69", Some(rest)) => ("6/9", rest),
+                    ("69", None) => ("6/9", rest),
+                    (n, _) => (n, rest),
+                };
+                if !matches!(
+                    n,
+                    "2" | "4" | "5" | "6" | "7" | "9" | "11" | "13" | "6/9"
+                ) {
+                    return Err(err(format!("unknown suffix {s:?}")));
+                }
+                chord.extensions.push(n.to_string());
+                s = rest;
+            }
+        }
+        Ok(chord)
+    }
+}
+
+impl fmt::Display for Chord {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        write!(out, "{}{}{}", self.root, self.quality, self.suffix())?;
+        if let Some(bass) = self.bass {
+            write!(out, "/{bass}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Quality {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        out.write_str(match self {
+            Quality::Minor => "m",
+            Quality::Diminished => "dim",
+            Quality::Augmented => "+",
+            // Suspensions are written after the extensions.
+            _ => "",
+        })
+    }
+}
+
+impl Note {
+    /// Parse a note at the start of `name`, returning the note and
+    /// the rest of the name.
+    pub fn parse_prefix(name: &str) -> Option<(Note, &str)> {
+        let mut chars = name.chars();
+        let letter = match chars.next()? {
+            'H' => 'B',
+            letter @ 'A'..='G' => letter,
+            _ => return None,
+        };
+        let rest = chars.as_str();
+        let (accidental, rest) =
+            if let Some(rest) = strip_any(rest, &["#", "♯"]) {
+                (Accidental::Sharp, rest)
+            } else if let Some(rest) = strip_any(rest, &["b", "♭"]) {
+                (Accidental::Flat, rest)
+            } else {
+                (Accidental::Natural, rest)
+            };
+        Some((Note { letter, accidental }, rest))
+    }
+
+    /// The number of semitones from A to this note.
+    pub fn semitone(self) -> i8 {
+        let natural: i8 = match self.letter {
+            'A' => 0,
+            'B' => 2,
+            'C' => 3,
+            'D' => 5,
+            'E' => 7,
+            'F' => 8,
+            _ => 10,
+        };
+        let offset = match self.accidental {
+            Accidental::Natural => 0,
+            Accidental::Sharp => 1,
+            Accidental::Flat => -1,
+        };
+        (natural + offset).rem_euclid(12)
+    }
+
+    /// The note transposed by `semitones`.
+    ///
+    /// A note with an accidental keeps its kind (sharp or flat), other
+    /// notes are named as commonly used in chord charts.
+    pub fn transpose(self, semitones: i8) -> Note {
+        self.transpose_as(semitones, self.accidental)
+    }
+
+    /// The note transposed by `semitones`, named with the `accidental`
+    /// kind if it needs an accidental (or as commonly used in chord
+    /// charts for `Natural`).
+    fn transpose_as(self, semitones: i8, accidental: Accidental) -> Note {
+        let names = match accidental {
+            Accidental::Sharp => &SHARP_NOTE,
+            Accidental::Flat => &FLAT_NOTE,
+            Accidental::Natural => &COMMON_NOTE,
+        };
+        let semitone = (self.semitone() + semitones).rem_euclid(12);
+        names[semitone as usize].parse().unwrap()
+    }
+
+    /// All names for this note (with at most one accidental).
+    pub fn spellings(self) -> Vec<Note> {
+        let accidentals =
+            [Accidental::Natural, Accidental::Sharp, Accidental::Flat];
+        let mut notes = vec![self];
+        for letter in 'A'..='G' {
+            for accidental in accidentals {
+                let note = Note { letter, accidental };
+                if note.semitone() == self.semitone() && note != self {
+                    notes.push(note);
+                }
+            }
+        }
+        notes
+    }
+}
+
+impl FromStr for Note {
+    type Err = ChordError;
+
+    fn from_str(name: &str) -> Result<Note, ChordError> {
+        match Note::parse_prefix(name) {
+            Some((note, "")) => Ok(note),
+            _ => Err(ChordError {
+                reason: format!("{name:?} is not a note"),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Note {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        write!(out, "{}", self.letter)?;
+        match self.accidental {
+            Accidental::Natural => Ok(()),
+            Accidental::Sharp => out.write_str("#"),
+            Accidental::Flat => out.write_str("b"),
+        }
+    }
+}
+
+/// Check if a name in a chord position is not meant to be a chord.
+///
+/// That is "no chord" (`N.C.`), repeat marks (`%`, `x2`), and a bass
+/// note or beats without a chord (`/G`, `/`).
+pub fn is_no_chord(name: &str) -> bool {
+    matches!(name, "" | "NC" | "N.C." | "%" | "-")
+        || name.starts_with('/')
+        || name
+            .strip_prefix('x')
+            .is_some_and(|n| n.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Transpose a chord name by `semitones`.
+///
+/// A name that is not a chord is returned unchanged.
+pub fn transpose(name: &str, semitones: i8) -> String {
+    match name.parse::<Chord>() {
+        Ok(chord) if semitones != 0 => chord.transpose(semitones).to_string(),
+        _ => name.to_string(),
+    }
+}
+
+fn strip_any<'a>(s: &'a str, prefixes: &[&str]) -> Option<&'a str> {
+    prefixes.iter().find_map(|prefix| s.strip_prefix(prefix))
+}
+
+/// Strip a flat or sharp sign, returning true for flat.
+fn strip_accidental(s: &str) -> Option<(bool, &str)> {
+    if let Some(rest) = strip_any(s, &["b", "♭", "-"]) {
+        Some((true, rest))
+    } else {
+        strip_any(s, &["#", "♯", "+"]).map(|rest| (false, rest))
+    }
+}
+
+fn split_number(s: &str) -> (&str, &str) {
+    s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()))
+}
+
+static SHARP_NOTE: [&str; 12] = [
+    "A", "A#", "B", "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#",
+];
+static FLAT_NOTE: [&str; 12] = [
+    "A", "Bb", "B", "C", "Db", "D", "Eb", "E", "F", "Gb", "G", "Ab",
+];
+/// Note names, from A, as commonly used in chord charts.
+static COMMON_NOTE: [&str; 12] = [
+    "A", "Bb", "B", "C", "C#", "D", "Eb", "E", "F", "F#", "G", "Ab",
+];
+
+#[test]
+fn test_parse_chord() {
+    let chord = "F#m7b5/E".parse::<Chord>().unwrap();
+    assert_eq!(chord.root.to_string(), "F#");
+    assert_eq!(chord.quality, Quality::Minor);
+    assert_eq!(chord.extensions, vec!["7".to_string()]);
+    assert_eq!(chord.alterations, vec!["b5".to_string()]);
+    assert_eq!(chord.bass.map(|b| b.to_string()), Some("E".into()));
+    let canonical = |name: &str| name.parse::<Chord>().unwrap().to_string();
+    assert_eq!(canonical("Asus"), "Asus4");
+    assert_eq!(canonical("C7sus4"), "C7sus4");
+    assert_eq!(canonical("Cmin7"), "Cm7");
+    assert_eq!(canonical("CΔ"), "Cmaj7");
+    assert_eq!(canonical("Cmaj"), "C");
+    assert_eq!(canonical("CM"), "C");
+    assert_eq!(canonical("CM7"), "Cmaj7");
+    assert_eq!(canonical("Dm(maj7)"), "Dmmaj7");
+    assert_eq!(canonical("Eb69"), "Eb6/9");
+    assert_eq!(canonical("G7(#9,b13)"), "G7#9b13");
+    assert_eq!(canonical("Bø"), "Bm7b5");
+    assert_eq!(canonical("Hm"), "Bm");
+    assert_eq!(canonical("G+"), "G+");
+    assert_eq!(canonical("Eadd9/G#"), "Eadd9/G#");
+    assert!("Smaj9".parse::<Chord>().is_err());
+    assert!("N.C.".parse::<Chord>().is_err());
+    assert!("Cfoo".parse::<Chord>().is_err());
+}
+
+#[test]
+fn test_spellings() {
+    let chord = "C#m/G#".parse::<Chord>().unwrap();
+    assert_eq!(
+        chord.spellings(),
+        vec!["C#m/G#", "C#m/Ab", "Dbm/G#", "Dbm/Ab"],
+    );
+    let chord = "Cb".parse::<Chord>().unwrap();
+    assert_eq!(chord.spellings(), vec!["Cb", "B"]);
+}
+
+#[test]
+fn test_transpose() {
+    assert_eq!(transpose("C", 2), "D");
+    assert_eq!(transpose("Am7", 1), "Bbm7");
+    assert_eq!(transpose("C/G", -1), "B/F#");
+    assert_eq!(transpose("C/G", 1), "C#/G#");
+    assert_eq!(transpose("D/F#", 1), "Eb/G");
+    assert_eq!(transpose("F#m7b5", 1), "Gm7b5");
+    assert_eq!(transpose("Ebmaj7", -3), "Cmaj7");
+    assert_eq!(transpose("G#", 3), "B");
+    assert_eq!(transpose("Hm", 12), "Bm");
+    assert_eq!(transpose("N.C.", 2), "N.C.");
+    assert_eq!(transpose("%", 2), "%");
+}
+
+#[test]
+fn test_intervals() {
+    let intervals = |name: &str| name.parse::<Chord>().unwrap().intervals();
+    assert_eq!(intervals("C"), vec![(0, true), (4, true), (7, false)]);
+    assert_eq!(intervals("C5"), vec![(0, true), (7, true)]);
+    assert_eq!(
+        intervals("Cm7b5"),
+        vec![(0, true), (3, true), (6, true), (10, true)],
+    );
+    assert_eq!(
+        intervals("Cdim7"),
+        vec![(0, true), (3, true), (6, true), (9, true)],
+    );
+    assert_eq!(
+        intervals("C7#9"),
+        vec![(0, true), (4, true), (7, false), (10, true), (3, true)],
+    );
+    let c11 = [(0, true), (4, true), (7, false), (10, true), (2, false)];
+    assert_eq!(intervals("C11"), [&c11[..], &[(5, true)]].concat());
+}
@@ -0,0 +1,111 @@
+//! Chord definitions loaded from files, to extend the built-in chords.
+//!
+//! A chord db file is either a chopro file, where all `{define}`
+//! directives are used, or a simple file with the argument of a
+//! `{define}` on each line, optionally prefixed by a selector and a
+//! colon:
+//!
+//! ```text
+//! # Our house chords
+//! Am7 base-fret 1 frets x 0 2 0 1 0
+//! ukulele: Am7 frets 0 0 0 0
+//! ```
+use crate::diagnostics::{Code, Diagnostics, Pos};
+use crate::parser::{is_selected, parse_define};
+use crate::song::{ChordDef, Song};
+use std::fs::read_to_string;
+use std::io;
+use std::path::Path;
+
+/// Load chord definitions from a file.
+///
+/// See `parse` for the arguments.
+pub fn load(
+    path: &Path,
+    diagnostics: &Diagnostics,
+    selectors: &[String],
+) -> io::Result<Vec<ChordDef>> {
+    Ok(parse(&read_to_string(path)?, path, diagnostics, selectors))
+}
+
+/// Parse chord definitions from a chopro or simple chord db source.
+///
+/// Definitions with a selector are only used if it matches one of
+/// the `selectors`.  Problems are reported to `diagnostics`.
+pub fn parse(
+    source: &str,
+    path: &Path,
+    diagnostics: &Diagnostics,
+    selectors: &[String],
+) -> Vec<ChordDef> {
+    let is_chopro = source.lines().any(|l| l.trim_start().starts_with('{'));
+    if is_chopro {
+        return Song::parse(source.as_bytes(), path, diagnostics, selectors)
+            .into_iter()
+            .flat_map(|song| song.chord_defs)
+            .collect();
+    }
+    let mut defs = vec![];
+    for (i, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let pos = Pos::new(i + 1, 1);
+        let arg = match line.split_once(char::is_whitespace) {
+            Some((sel, rest)) if sel.ends_with(':') => {
+                if !is_selected(&sel[..sel.len() - 1], selectors) {
+                    continue;
+                }
+                rest
+            }
+            _ => line,
+        };
+        match parse_define(arg) {
+            Some((name, def, fingers)) => defs.push(ChordDef {
+                pos,
+                name,
+                def,
+                fingers,
+            }),
+            None => diagnostics.warning(
+                pos,
+                Code::BadChordDef,
+                format_args!("Bad chord definition {line}"),
+            ),
+        }
+    }
+    defs
+}
+
+#[test]
+fn test_parse_simple() {
+    let source = "# House chords\n\
+                  Am7 base-fret 1 frets x 0 2 0 1 0\n\
+                  \n\
+                  ukulele: Am7 frets 0 0 0 0\n\
+                  mandolin: Am7 frets 2 2 3 3\n\
+                  Q frets\n";
+    let diagnostics = Diagnostics::new("test");
+    let selectors = ["ukulele".to_string()];
+    let defs = parse(source, Path::new("test"), &diagnostics, &selectors);
+    let defs = defs.iter().map(|d| (&*d.name, &*d.def)).collect::<Vec<_>>();
+    assert_eq!(
+        defs,
+        vec![
+            ("Am7", &[1, -1, 0, 2, 0, 1, 0][..]),
+            ("Am7", &[1, 0, 0, 0, 0])
+        ],
+    );
+    assert_eq!(diagnostics.n_warnings(), 1);
+}
+
+#[test]
+fn test_parse_chopro() {
+    let source = "{define: Am7 frets x 0 2 0 1 0}\n\
+                  {define-mandolin: Am7 frets 2 2 3 3}\n";
+    let diagnostics = Diagnostics::new("test");
+    let defs = parse(source, Path::new("test"), &diagnostics, &[]);
+    assert_eq!(defs.len(), 1);
+    assert_eq!(defs[0].def, vec![1, -1, 0, 2, 0, 1, 0]);
+}
@@ -1,6 +1,11 @@
+use crate::chord::{is_no_chord, transpose, Chord, Note};
+use crate::diagnostics::Pos;
+use crate::song::ChordDef;
+use crate::voicing::voicings;
 use clap::ValueEnum;
 use lazy_static::lazy_static;
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::BTreeMap;
+use std::str::FromStr;
 use std::vec::Vec;
 
 #[derive(PartialEq, Debug, Default, Copy, Clone, ValueEnum)]
@@ -10,110 +15,462 @@ pub enum Instrument {
     Guitar,
     /// Mandolin in g-d-a-e tuning.
     Mandolin,
+    /// Ukulele in g-c-e-a tuning (with a high g).
+    Ukulele,
     /// Don't render chordboxes.
     None,
 }
 
+impl Instrument {
+    /// The name of the instrument, as used in directive selectors.
+    pub fn name(self) -> &'static str {
+        match self {
+            Instrument::Guitar => "guitar",
+            Instrument::Mandolin => "mandolin",
+            Instrument::Ukulele => "ukulele",
+            Instrument::None => "none",
+        }
+    }
+
+    /// The open note of each string, as semitones above A, from the
+    /// lowest string.
+    pub fn tuning(self) -> &'static [i8] {
+        match self {
+            Instrument::Guitar => &[7, 0, 5, 10, 2, 7],
+            Instrument::Mandolin => &[10, 5, 0, 7],
+            Instrument::Ukulele => &[10, 3, 7, 0],
+            Instrument::None => &[],
+        }
+    }
+}
+
+/// The tuning of a fretted instrument.
+///
+/// A tuning is given either as a named preset (such as `dadgad` or
+/// `banjo`), or as comma-separated notes from the lowest string, such
+/// as `D,A,D,G,A,D`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tuning {
+    name: Option<String>,
+    /// The open note of each string, as semitones above A.
+    notes: Vec<i8>,
+}
+
+impl Tuning {
+    /// The name of a preset tuning, as used in directive selectors.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// The open note of each string, as semitones above A, from the
+    /// lowest string.
+    pub fn notes(&self) -> &[i8] {
+        &self.notes
+    }
+}
+
+impl FromStr for Tuning {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Tuning, String> {
+        let preset = TUNINGS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(s.trim()));
+        let (name, notes) = match preset {
+            Some((name, notes)) => (Some(name.to_string()), *notes),
+            None => (None, s),
+        };
+        let notes = notes
+            .split(',')
+            .map(|note| {
+                note.trim()
+                    .parse::<Note>()
+                    .map(Note::semitone)
+                    .map_err(|e| e.to_string())
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if notes.len() < 2 {
+            return Err(format!("A tuning needs at least two strings: {s:?}"));
+        }
+        Ok(Tuning { name, notes })
+    }
+}
+
+/// Named tunings, with notes from the lowest string.
+static TUNINGS: &[(&str, &str)] = &[
+    ("guitar", "E,A,D,G,B,E"),
+    ("drop-d", "D,A,D,G,B,E"),
+    ("dadgad", "D,A,D,G,A,D"),
+    ("open-d", "D,A,D,F#,A,D"),
+    ("open-g", "D,G,D,G,B,D"),
+    ("banjo", "G,D,G,B,D"),
+    ("tenor-guitar", "C,G,D,A"),
+    ("bass", "E,A,D,G"),
+    ("mandolin", "G,D,A,E"),
+    ("ukulele", "G,C,E,A"),
+];
+
+/// A table of chord definitions by name.
+type Table = BTreeMap<&'static str, Vec<i8>>;
+
 pub struct ChordHolder {
-    unknown_chord: &'static Vec<i8>,
-    known_chords: &'static BTreeMap<&'static str, Vec<i8>>,
+    unknown_chord: Vec<i8>,
+    known_chords: &'static Table,
+    /// Definitions from chord db files, overriding the known chords.
+    library: BTreeMap<String, Vec<i8>>,
     local: BTreeMap<String, Vec<i8>>,
-    used: BTreeSet<String>,
+    /// Open note of each string, for generating unknown chords.
+    tuning: Vec<i8>,
+    /// The number of frets shown in a chord box.
+    n_frets: u8,
+    /// The fret of the capo, 0 for no capo.
+    capo: u8,
+    /// Generated definitions for chords that are not known.
+    generated: BTreeMap<String, Vec<i8>>,
+    /// Fingers (0 for none) for each string of local definitions.
+    fingers: BTreeMap<String, Vec<u8>>,
+    /// Used chords, with the position where each was first used.
+    used: BTreeMap<String, Pos>,
 }
 
 impl ChordHolder {
     pub fn new_for(instrument: Instrument) -> Self {
-        match instrument {
-            Instrument::Guitar => ChordHolder {
-                unknown_chord: &UNKNOWN_CHORD,
-                known_chords: &KNOWN_CHORDS,
-                local: BTreeMap::new(),
-                used: BTreeSet::new(),
-            },
-            Instrument::Mandolin => ChordHolder {
-                unknown_chord: &UNKNOWN_MANDOLIN_CHORD,
-                known_chords: &KNOWN_MANDOLIN_CHORDS,
-                local: BTreeMap::new(),
-                used: BTreeSet::new(),
-            },
-            Instrument::None => ChordHolder {
-                unknown_chord: &UNKNOWN_NONE_CHORD,
-                known_chords: &KNOWN_NONE_CHORDS,
-                local: BTreeMap::new(),
-                used: BTreeSet::new(),
-            },
+        let (unknown_chord, known_chords, n_frets): (&Vec<i8>, &Table, u8) =
+            match instrument {
+                Instrument::Guitar => (&UNKNOWN_CHORD, &KNOWN_CHORDS, 4),
+                Instrument::Mandolin => {
+                    (&UNKNOWN_MANDOLIN_CHORD, &KNOWN_MANDOLIN_CHORDS, 8)
+                }
+                Instrument::Ukulele => {
+                    (&UNKNOWN_UKULELE_CHORD, &KNOWN_UKULELE_CHORDS, 5)
+                }
+                Instrument::None => {
+                    (&UNKNOWN_NONE_CHORD, &KNOWN_NONE_CHORDS, 4)
+                }
+            };
+        ChordHolder {
+            unknown_chord: unknown_chord.clone(),
+            known_chords,
+            library: BTreeMap::new(),
+            local: BTreeMap::new(),
+            tuning: instrument.tuning().to_vec(),
+            n_frets,
+            capo: 0,
+            generated: BTreeMap::new(),
+            fingers: BTreeMap::new(),
+            used: BTreeMap::new(),
+        }
+    }
+
+    /// Use a custom tuning rather than the standard tuning of the
+    /// instrument.
+    ///
+    /// The built-in chords are only used if the tuning is the
+    /// standard tuning, otherwise all chords that are not defined in
+    /// the song are generated.
+    pub fn with_tuning(self, tuning: &Tuning) -> Self {
+        if tuning.notes == self.tuning {
+            return self;
+        }
+        let mut unknown_chord = vec![-2; tuning.notes.len() + 1];
+        unknown_chord[0] = 0;
+        ChordHolder {
+            unknown_chord,
+            known_chords: &KNOWN_NONE_CHORDS,
+            tuning: tuning.notes.clone(),
+            ..self
         }
     }
-    pub fn use_chord(&mut self, chord: &str) {
-        if !(chord.is_empty()
-            || chord == "NC"
-            || chord == "N.C."
-            || chord == "%"
-            || chord == "-"
-            || chord.starts_with('/')
-            || chord.starts_with('x'))
-        {
-            self.used.insert(chord.to_string());
+
+    /// Add chord definitions, e.g. from chord db files, on top of the
+    /// known chords.
+    ///
+    /// Definitions for another instrument (i.e. with another number
+    /// of strings), or that don't fit in a chord box (see
+    /// `fit_shape`), are ignored.
+    pub fn with_library(mut self, defs: &[ChordDef]) -> Self {
+        for def in defs {
+            if def.def.len() == self.unknown_chord.len() {
+                let Some(shape) = fit_shape(&def.def, self.n_frets) else {
+                    continue;
+                };
+                if def.fingers.is_empty() {
+                    self.fingers.remove(&def.name);
+                } else {
+                    self.fingers.insert(def.name.clone(), def.fingers.clone());
+                }
+                self.library.insert(def.name.clone(), shape);
+            }
         }
+        self
     }
-    pub fn define(&mut self, chord: String, def: Vec<i8>) {
-        if def.len() == self.unknown_chord.len() {
+
+    /// The number of frets shown in a chord box.
+    pub fn n_frets(&self) -> u8 {
+        self.n_frets
+    }
+
+    /// Set the fret of the capo, 0 for no capo.
+    ///
+    /// Chords are still named, defined and drawn as the shapes played
+    /// relative to the capo, see `sounding` for the actual chord.
+    pub fn set_capo(&mut self, capo: u8) {
+        self.capo = capo;
+    }
+
+    /// The fret of the capo, 0 for no capo.
+    pub fn capo(&self) -> u8 {
+        self.capo
+    }
+
+    /// The name of the chord that sounds when playing the shape
+    /// `name` with the capo.
+    pub fn sounding(&self, name: &str) -> String {
+        transpose(name, self.capo as i8)
+    }
+
+    pub fn use_chord(&mut self, chord: &str, pos: Pos) {
+        if !is_no_chord(chord) {
+            self.used.entry(chord.to_string()).or_insert(pos);
+            if self.lookup(chord).is_none() {
+                self.generate(chord);
+            }
+        }
+    }
+    /// Define a chord locally (for the current song).
+    ///
+    /// The `fingers` may be empty, or contain a finger for each string.
+    /// The definition is fitted in a chord box by `fit_shape`.
+    /// Returns false, and ignores the definition, if it is for
+    /// another instrument or does not fit in a chord box.
+    pub fn define(
+        &mut self,
+        chord: String,
+        def: Vec<i8>,
+        fingers: Vec<u8>,
+    ) -> bool {
+        let def = fit_shape(&def, self.n_frets);
+        if let Some(def) = def.filter(|d| d.len() == self.unknown_chord.len()) {
+            if fingers.is_empty() {
+                self.fingers.remove(&chord);
+            } else {
+                self.fingers.insert(chord.clone(), fingers);
+            }
             self.local.insert(chord, def);
+            true
         } else {
-            println!("Ignoring chord def {chord}, wrong instrument");
+            false
         }
     }
+    /// Get the fingers for a chord, if defined.
+    ///
+    /// The fingers are found by the same spelling as the definition,
+    /// see `lookup`.
+    pub fn get_fingers(&self, chord: &str) -> Option<&[u8]> {
+        let name = self.resolve(chord)?;
+        self.fingers.get(&name).map(|f| f.as_slice())
+    }
     pub fn get_used(&self) -> Vec<(&str, &Vec<i8>)> {
         self.used
-            .iter()
+            .keys()
             .map(|name| {
                 (
                     name as &str,
-                    self.local
-                        .get(name)
-                        .or_else(|| self.known_chords.get(name as &str))
-                        .or_else(|| {
-                            ChordHolder::replacement(name).and_then(|repl| {
-                                self.known_chords.get(&repl as &str)
-                            })
-                        })
-                        .unwrap_or_else(|| {
-                            if !self.unknown_chord.is_empty() {
-                                println!("Warning: Unknown chord {name}");
-                            }
-                            self.unknown_chord
-                        }),
+                    self.lookup(name).unwrap_or(&self.unknown_chord),
                 )
             })
             .collect()
     }
 
+    /// Use the chords used in `other`, with their definitions and
+    /// fingers, e.g. to collect the chords of all songs in a book.
+    ///
+    /// Chords that are already used keep their first definition.
+    pub fn use_all(&mut self, other: &ChordHolder) {
+        for (name, pos) in &other.used {
+            if self.used.contains_key(name) {
+                continue;
+            }
+            if let Some(def) = other.lookup(name) {
+                let fingers = other.get_fingers(name).unwrap_or_default();
+                self.define(name.clone(), def.clone(), fingers.to_vec());
+            }
+            self.used.insert(name.clone(), *pos);
+        }
+    }
+
+    /// Get the used chords that has no definition, with the position
+    /// where each was first used.
+    ///
+    /// A used chord that is not known gets a generated definition, so
+    /// this is only chords that are not valid chord names, or that has
+    /// no playable voicing on the instrument.
+    ///
+    /// This is always empty for an instrument without chordboxes.
+    pub fn get_unknown(&self) -> Vec<(&str, Pos)> {
+        if self.unknown_chord.is_empty() {
+            return vec![];
+        }
+        self.used
+            .iter()
+            .filter(|(name, _)| self.lookup(name).is_none())
+            .map(|(name, pos)| (name as &str, *pos))
+            .collect()
+    }
+
+    /// Find the definition of a chord.
+    ///
+    /// If there is no definition for the exact name, other spellings
+    /// of the same chord are tried, e.g. `A#m` for `Bbm` or `Hm` for
+    /// `Bm`.
+    fn lookup(&self, name: &str) -> Option<&Vec<i8>> {
+        self.get_def(&self.resolve(name)?)
+    }
+
+    /// Find the spelling of a chord that has a definition, see `lookup`.
+    fn resolve(&self, name: &str) -> Option<String> {
+        if self.get_def(name).is_some() {
+            return Some(name.to_string());
+        }
+        let chord = name.parse::<Chord>().ok()?;
+        let rest = Note::parse_prefix(name)?.1;
+        let as_written = chord
+            .root
+            .spellings()
+            .into_iter()
+            .map(|root| format!("{root}{rest}"));
+        chord
+            .spellings()
+            .into_iter()
+            .chain(as_written)
+            .find(|name| self.get_def(name).is_some())
+    }
+
+    fn get_def(&self, name: &str) -> Option<&Vec<i8>> {
+        self.local
+            .get(name)
+            .or_else(|| self.library.get(name))
+            .or_else(|| self.known_chords.get(name))
+            .or_else(|| self.generated.get(name))
+    }
+
+    /// Generate a definition for a chord that is not known, if it is
+    /// a valid chord name and has a playable voicing.
+    fn generate(&mut self, name: &str) {
+        if self.tuning.is_empty() {
+            return;
+        }
+        let Ok(chord) = name.parse::<Chord>() else {
+            return;
+        };
+        if let Some(def) = voicings(&chord, &self.tuning).into_iter().next() {
+            self.generated.insert(name.to_string(), def);
+        }
+    }
+
+    /// Get all known chords, including those from chord db files.
     pub fn get_all_chords(&self) -> Vec<(&str, &Vec<i8>)> {
-        self.known_chords.iter().map(|(a, b)| (*a, b)).collect()
+        let mut all = self
+            .known_chords
+            .iter()
+            .map(|(a, b)| (*a, b))
+            .collect::<BTreeMap<_, _>>();
+        all.extend(self.library.iter().map(|(a, b)| (a.as_str(), b)));
+        all.into_iter().collect()
     }
+}
 
-    fn replacement(name: &str) -> Option<String> {
-        if let Some(opts) = name.strip_prefix('H') {
-            Some(format!("B{opts}"))
-        } else if name.len() >= 2 {
-            match &name[..2] {
-                "A#" => Some(format!("Bb{}", &name[2..])),
-                "D#" => Some(format!("Eb{}", &name[2..])),
-                "Gb" => Some(format!("F#{}", &name[2..])),
-                "Cb" => Some(format!("B{}", &name[2..])),
-                _ => None,
+/// Fit a chord definition (base fret followed by a fret for each
+/// string) in a chord box showing `n_frets` frets.
+///
+/// Frets are relative to the base fret, but frets that would be drawn
+/// below the box are taken as absolute frets (as in `base-fret 10
+/// frets x 12 11 12 12 x`), and the shape is moved to start at its
+/// lowest fretted string.
+/// Returns None if the shape spans more frets than the box.
+pub fn fit_shape(def: &[i8], n_frets: u8) -> Option<Vec<i8>> {
+    let n_frets = n_frets as i8;
+    let frets = def.get(1..).unwrap_or_default();
+    let played = frets.iter().copied().filter(|f| *f > 0);
+    match (played.clone().min(), played.max()) {
+        (Some(low), Some(high)) if high > n_frets => {
+            if high - low >= n_frets {
+                return None;
             }
-        } else {
-            None
+            let mut result = vec![low];
+            result.extend(frets.iter().map(|&f| match f {
+                f if f > 0 => f - low + 1,
+                f => f,
+            }));
+            Some(result)
         }
+        _ => Some(def.to_vec()),
+    }
+}
+
+/// Move a chord shape (base fret followed by a fret for each string)
+/// to be `semitones` higher (or lower, if negative).
+///
+/// The shape is moved down if possible, otherwise up, so the result
+/// may be up to 11 frets from the original.  Open strings of the
+/// original shape becomes fretted.
+/// The result has absolute frets, so `fit_shape` can move it to fit
+/// the chord box of the instrument it is shown for.
+pub fn transpose_shape(def: &[i8], semitones: i8) -> Vec<i8> {
+    let Some((&base, frets)) = def.split_first() else {
+        return def.to_vec();
+    };
+    let up = semitones.rem_euclid(12);
+    if up == 0 {
+        return def.to_vec();
     }
+    let absolute = frets
+        .iter()
+        .map(|&f| if f > 0 { f + base.max(1) - 1 } else { f })
+        .collect::<Vec<_>>();
+    let lowest = absolute.iter().filter(|f| **f >= 0).min();
+    let shift = match lowest {
+        Some(&lowest) if lowest >= 12 - up => up - 12,
+        _ => up,
+    };
+    let mut result = vec![base.min(1)];
+    result.extend(absolute.iter().map(|&f| if f >= 0 { f + shift } else { f }));
+    result
+}
+
+#[test]
+fn test_fit_shape() {
+    let a9 = [10, -1, 12, 11, 12, 12, -1];
+    assert_eq!(fit_shape(&a9, 4), Some(vec![11, -1, 2, 1, 2, 2, -1]));
+    let c = [1, -1, 3, 2, 0, 1, 0];
+    assert_eq!(fit_shape(&c, 4), Some(c.to_vec()));
+    assert_eq!(fit_shape(&[1, 1, 7, 0, 0, 0, 0], 4), None);
+    assert_eq!(fit_shape(&[1, 1, 7, 0, 0], 8), Some(vec![1, 1, 7, 0, 0]));
+}
+
+#[test]
+fn test_transpose_shape() {
+    // E to F, the open strings are fretted by a barre.
+    assert_eq!(
+        transpose_shape(&[0, 0, 2, 2, 1, 0, 0], 1),
+        vec![0, 1, 3, 3, 2, 1, 1],
+    );
+    // C to D, moved up the neck, where it does not fit a guitar box
+    // from the nut, but does fit a longer mandolin box.
+    let d = transpose_shape(&[0, -1, 3, 2, 0, 1, 0], 2);
+    assert_eq!(d, vec![0, -1, 5, 4, 2, 3, 2]);
+    assert_eq!(fit_shape(&d, 4), Some(vec![2, -1, 4, 3, 1, 2, 1]));
+    assert_eq!(fit_shape(&d, 8), Some(d));
+    // Am at the fifth fret to Gm, moved down.
+    let gm = transpose_shape(&[5, 1, 3, 3, 1, 1, 1], -2);
+    assert_eq!(fit_shape(&gm, 4), Some(vec![3, 1, 3, 3, 1, 1, 1]));
 }
 
 #[test]
 fn test_simple_chord() {
     let mut test = ChordHolder::new_for(Instrument::Guitar);
-    test.use_chord("Am");
-    test.use_chord("E");
+    test.use_chord("Am", Pos::new(1, 2));
+    test.use_chord("E", Pos::new(1, 9));
     assert_eq!(
         vec![
             ("Am", &vec![0, -1, 0, 2, 2, 1, 0]),
@@ -126,9 +483,9 @@ fn test_simple_chord() {
 #[test]
 fn test_override_chord() {
     let mut test = ChordHolder::new_for(Instrument::Guitar);
-    test.define("Am".to_string(), vec![5, 1, 3, 3, 1, 1, 1]);
-    test.use_chord("Am");
-    test.use_chord("E");
+    test.define("Am".to_string(), vec![5, 1, 3, 3, 1, 1, 1], vec![]);
+    test.use_chord("Am", Pos::new(1, 2));
+    test.use_chord("E", Pos::new(1, 9));
     assert_eq!(
         vec![
             ("Am", &vec![5, 1, 3, 3, 1, 1, 1]),
@@ -138,15 +495,47 @@ fn test_override_chord() {
     )
 }
 
+#[test]
+fn test_define_fitted() {
+    let mut test = ChordHolder::new_for(Instrument::Guitar);
+    let a9 = vec![10, -1, 12, 11, 12, 12, -1];
+    assert!(test.define("A9".to_string(), a9, vec![]));
+    assert!(!test.define("Q".to_string(), vec![1, 1, 7, 0, 0, 0, 0], vec![]));
+    test.use_chord("A9", Pos::new(1, 2));
+    assert_eq!(vec![("A9", &vec![11, -1, 2, 1, 2, 2, -1])], test.get_used());
+}
+
+#[test]
+fn test_use_all() {
+    let mut book = ChordHolder::new_for(Instrument::Guitar);
+    let mut song = ChordHolder::new_for(Instrument::Guitar);
+    song.define("Am".to_string(), vec![5, 1, 3, 3, 1, 1, 1], vec![]);
+    song.use_chord("Am", Pos::new(1, 2));
+    book.use_all(&song);
+    let mut song = ChordHolder::new_for(Instrument::Guitar);
+    song.use_chord("Am", Pos::new(1, 2));
+    song.use_chord("E", Pos::new(1, 9));
+    book.use_all(&song);
+    assert_eq!(
+        vec![
+            ("Am", &vec![5, 1, 3, 3, 1, 1, 1]),
+            ("E", &vec![0, 0, 2, 2, 1, 0, 0]),
+        ],
+        book.get_used()
+    )
+}
+
 #[test]
 fn test_nochord_and_unknown() {
     let mut test = ChordHolder::new_for(Instrument::Guitar);
-    test.use_chord("N.C.");
-    test.use_chord("Smaj9");
+    test.use_chord("N.C.", Pos::new(1, 2));
+    test.use_chord("Smaj9", Pos::new(2, 2));
+    test.use_chord("Smaj9", Pos::new(3, 2));
     assert_eq!(
         vec![("Smaj9", &vec![0, -2, -2, -2, -2, -2, -2])],
         test.get_used()
-    )
+    );
+    assert_eq!(vec![("Smaj9", Pos::new(2, 2))], test.get_unknown());
 }
 
 lazy_static! {
@@ -351,8 +740,280 @@ lazy_static! {
     }
     result
     };
+    static ref UNKNOWN_UKULELE_CHORD: Vec<i8> = vec![0,-2,-2,-2,-2];
+    static ref KNOWN_UKULELE_CHORDS: BTreeMap<&'static str, Vec<i8>> = {
+    let mut result = BTreeMap::new();
+    {
+        let mut chord = |name: &'static str,
+                         g: i8, c: i8, e: i8, a: i8| {
+            result.insert(name, vec!(0, g, c, e, a));
+                         };
+        chord("A",      2, 1, 0, 0);
+        chord("A6",     2, 1, 2, 0);
+        chord("A7",     0, 1, 0, 0);
+        chord("A+",     2, 1, 1, 4);
+        chord("Adim7",  2, 3, 2, 3);
+        chord("Am",     2, 0, 0, 0);
+        chord("Am6",    2, 4, 2, 3);
+        chord("Am7",    0, 0, 0, 0);
+        chord("Amaj7",  1, 1, 0, 0);
+        chord("Asus2",  2, 4, 5, 2);
+        chord("Asus4",  2, 2, 0, 0);
+
+        chord("Ab",     5, 3, 4, 3);
+        chord("Ab7",    1, 3, 2, 3);
+        chord("Abdim7", 1, 2, 1, 2);
+        chord("Abm",    4, 3, 4, 2);
+        chord("Abm7",   1, 3, 2, 2);
+        chord("Abmaj7", 1, 3, 3, 3);
+
+        chord("B",      4, 3, 2, 2);
+        chord("B7",     2, 3, 2, 2);
+        chord("Bdim7",  1, 2, 1, 2);
+        chord("Bm",     4, 2, 2, 2);
+        chord("Bm7",    2, 2, 2, 2);
+        chord("Bmaj7",  3, 3, 2, 2);
+        chord("Bsus4",  4, 4, 2, 2);
+
+        chord("Bb",     3, 2, 1, 1);
+        chord("Bb6",    0, 2, 1, 1);
+        chord("Bb7",    1, 2, 1, 1);
+        chord("Bbdim7", 0, 1, 0, 1);
+        chord("Bbm",    3, 1, 1, 1);
+        chord("Bbm7",   1, 1, 1, 1);
+        chord("Bbmaj7", 3, 2, 1, 0);
+        chord("Bbsus4", 3, 3, 1, 1);
+
+        chord("C",      0, 0, 0, 3);
+        chord("C6",     0, 0, 0, 0);
+        chord("C7",     0, 0, 0, 1);
+        chord("Cadd9",  0, 2, 0, 3);
+        chord("C+",     1, 0, 0, 3);
+        chord("Cdim",   5, 3, 2, 3);
+        chord("Cdim7",  2, 3, 2, 3);
+        chord("Cm",     0, 3, 3, 3);
+        chord("Cm7",    3, 3, 3, 3);
+        chord("Cmaj7",  0, 0, 0, 2);
+        chord("Csus2",  0, 2, 3, 3);
+        chord("Csus4",  0, 0, 1, 3);
+
+        chord("C#",     1, 1, 1, 4);
+        chord("C#7",    1, 1, 1, 2);
+        chord("C#+",    2, 1, 1, 0);
+        chord("C#dim",  0, 1, 0, 4);
+        chord("C#dim7", 0, 1, 0, 1);
+        chord("C#m",    1, 1, 0, 4);
+        chord("C#m7",   1, 1, 0, 2);
+        chord("C#maj7", 1, 1, 1, 3);
+        chord("C#sus4", 1, 1, 2, 4);
+
+        chord("D",      2, 2, 2, 0);
+        chord("D6",     2, 2, 2, 2);
+        chord("D7",     2, 2, 2, 3);
+        chord("D+",     3, 2, 2, 1);
+        chord("Ddim7",  1, 2, 1, 2);
+        chord("Dm",     2, 2, 1, 0);
+        chord("Dm7",    2, 2, 1, 3);
+        chord("Dmaj7",  2, 2, 2, 4);
+        chord("Dsus2",  2, 2, 0, 0);
+        chord("Dsus4",  0, 2, 3, 0);
+
+        chord("Eb",     0, 3, 3, 1);
+        chord("Eb7",    3, 3, 3, 4);
+        chord("Eb+",    0, 3, 3, 2);
+        chord("Ebdim7", 2, 3, 2, 3);
+        chord("Ebm",    3, 3, 2, 1);
+        chord("Ebm7",   3, 3, 2, 4);
+        chord("Ebmaj7", 3, 3, 3, 5);
+        chord("Ebsus4", 1, 3, 4, 1);
+
+        chord("E",      1, 4, 0, 2); // also 4 4 4 2
+        chord("E6",     1, 1, 0, 2);
+        chord("E7",     1, 2, 0, 2);
+        chord("E+",     1, 0, 0, 3);
+        chord("Edim7",  0, 1, 0, 1);
+        chord("Em",     0, 4, 3, 2);
+        chord("Em7",    0, 2, 0, 2);
+        chord("Emaj7",  1, 3, 0, 2);
+        chord("Esus4",  4, 4, 5, 2);
+
+        chord("F",      2, 0, 1, 0);
+        chord("F6",     2, 2, 1, 3);
+        chord("F7",     2, 3, 1, 0);
+        chord("F+",     2, 1, 1, 0);
+        chord("Fdim7",  1, 2, 1, 2);
+        chord("Fm",     1, 0, 1, 3);
+        chord("Fm7",    1, 3, 1, 3);
+        chord("Fmaj7",  2, 4, 1, 3);
+        chord("Fsus2",  0, 0, 1, 3);
+        chord("Fsus4",  3, 0, 1, 1);
+
+        chord("F#",     3, 1, 2, 1);
+        chord("F#7",    3, 4, 2, 4);
+        chord("F#dim7", 2, 3, 2, 3);
+        chord("F#m",    2, 1, 2, 0);
+        chord("F#m7",   2, 4, 2, 4);
+        chord("F#maj7", 3, 5, 2, 4);
+        chord("F#sus4", 4, 1, 2, 2);
+
+        chord("G",      0, 2, 3, 2);
+        chord("G6",     0, 2, 0, 2);
+        chord("G7",     0, 2, 1, 2);
+        chord("G+",     0, 3, 3, 2);
+        chord("Gdim7",  0, 1, 0, 1);
+        chord("Gm",     0, 2, 3, 1);
+        chord("Gm7",    0, 2, 1, 1);
+        chord("Gmaj7",  0, 2, 2, 2);
+        chord("Gsus2",  0, 2, 3, 0);
+        chord("Gsus4",  0, 2, 3, 3);
+    }
+    result
+    };
+
     static ref UNKNOWN_NONE_CHORD: Vec<i8> = vec![];
     static ref KNOWN_NONE_CHORDS: BTreeMap<&'static str, Vec<i8>> = {
         BTreeMap::new()
     };
 }
+
+#[test]
+fn test_enharmonic_chord() {
+    let mut test = ChordHolder::new_for(Instrument::Guitar);
+    test.use_chord("A#m", Pos::new(1, 2));
+    test.use_chord("Hm", Pos::new(1, 6));
+    test.use_chord("x2", Pos::new(1, 9));
+    assert_eq!(
+        test.get_used(),
+        vec![
+            ("A#m", &vec![1, -1, 1, 3, 3, 2, 1]),
+            ("Hm", &vec![2, -1, 1, 3, 3, 2, 1]),
+        ]
+    );
+    assert!(test.get_unknown().is_empty());
+}
+
+#[test]
+fn test_enharmonic_fingers() {
+    let mut test = ChordHolder::new_for(Instrument::Guitar);
+    let shape = vec![1, -1, 1, 3, 3, 3, 1];
+    let fingers = vec![0, 1, 2, 3, 4, 1];
+    test.define("Bb".into(), shape.clone(), fingers.clone());
+    test.use_chord("A#", Pos::new(1, 2));
+    assert_eq!(test.get_used(), vec![("A#", &shape)]);
+    assert_eq!(test.get_fingers("A#"), Some(&fingers[..]));
+    assert_eq!(test.get_fingers("A#m"), None);
+}
+
+#[test]
+fn test_generated_chord() {
+    let mut test = ChordHolder::new_for(Instrument::Guitar);
+    test.use_chord("Fadd9", Pos::new(1, 2));
+    assert_eq!(test.get_used(), vec![("Fadd9", &vec![1, 1, 0, 3, 0, 1, 1])]);
+    assert!(test.get_unknown().is_empty());
+}
+
+#[test]
+fn test_tuning() {
+    let dadgad = "D, A, D, G, A, D".parse::<Tuning>().unwrap();
+    assert_eq!(dadgad.name(), None);
+    assert_eq!(dadgad.notes(), &[5, 0, 5, 10, 0, 5]);
+    assert_eq!("DADGAD".parse::<Tuning>().unwrap().notes(), dadgad.notes());
+    assert_eq!("Banjo".parse::<Tuning>().unwrap().name(), Some("banjo"));
+    assert!("D,A,X".parse::<Tuning>().is_err());
+
+    let standard = "guitar".parse::<Tuning>().unwrap();
+    let mut test =
+        ChordHolder::new_for(Instrument::Guitar).with_tuning(&standard);
+    test.use_chord("Am", Pos::new(1, 2));
+    assert_eq!(test.get_used(), vec![("Am", &vec![0, -1, 0, 2, 2, 1, 0])]);
+
+    let mut test = ChordHolder::new_for(Instrument::Guitar)
+        .with_tuning(&"tenor-guitar".parse().unwrap());
+    test.use_chord("G", Pos::new(1, 2));
+    test.use_chord("Smaj9", Pos::new(1, 5));
+    assert_eq!(
+        test.get_used(),
+        vec![
+            ("G", &vec![1, -1, 0, 0, 2]),
+            ("Smaj9", &vec![0, -2, -2, -2, -2])
+        ]
+    );
+}
+
+#[test]
+fn test_ukulele_chords() {
+    let tuning = Instrument::Ukulele.tuning();
+    for (name, def) in KNOWN_UKULELE_CHORDS.iter() {
+        let chord = name.parse::<Chord>().unwrap();
+        let root = chord.root.semitone();
+        let tones = chord
+            .intervals()
+            .into_iter()
+            .map(|(i, essential)| ((root + i).rem_euclid(12), essential))
+            .collect::<Vec<_>>();
+        let notes = def[1..]
+            .iter()
+            .zip(tuning)
+            .map(|(fret, open)| (open + fret).rem_euclid(12))
+            .collect::<Vec<_>>();
+        for note in &notes {
+            assert!(tones.iter().any(|(t, _)| t == note), "{name}: {note}");
+        }
+        for (tone, essential) in tones {
+            assert!(!essential || notes.contains(&tone), "{name}: {tone}");
+        }
+    }
+    let mut test = ChordHolder::new_for(Instrument::Ukulele);
+    test.use_chord("A#m7", Pos::new(1, 2));
+    test.use_chord("Caug", Pos::new(1, 8));
+    assert_eq!(
+        test.get_used(),
+        vec![
+            ("A#m7", &vec![0, 1, 1, 1, 1]),
+            ("Caug", &vec![0, 1, 0, 0, 3]),
+        ]
+    );
+    assert!(test.get_unknown().is_empty());
+}
+
+#[test]
+fn test_capo() {
+    let mut test = ChordHolder::new_for(Instrument::Guitar);
+    test.set_capo(2);
+    test.use_chord("G", Pos::new(1, 2));
+    assert_eq!(test.sounding("G"), "A");
+    assert_eq!(test.sounding("Em7/D"), "F#m7/E");
+    assert_eq!(test.sounding("N.C."), "N.C.");
+    assert_eq!(test.get_used(), vec![("G", &vec![0, 3, 2, 0, 0, 0, 3])]);
+}
+
+#[test]
+fn test_library() {
+    let def = |name: &str, def: Vec<i8>| ChordDef {
+        pos: Pos::new(1, 1),
+        name: name.into(),
+        def,
+        fingers: vec![],
+    };
+    let library = [
+        def("Am", vec![5, 1, 3, 3, 1, 1, 1]),
+        def("Qm", vec![1, 0, 0, 0, 0, 0, 0]),
+        def("Am", vec![0, 2, 2, 3, 5]),
+    ];
+    let mut test =
+        ChordHolder::new_for(Instrument::Guitar).with_library(&library);
+    test.use_chord("Am", Pos::new(1, 2));
+    test.use_chord("Qm", Pos::new(1, 6));
+    test.use_chord("C", Pos::new(1, 9));
+    assert_eq!(
+        test.get_used(),
+        vec![
+            ("Am", &vec![5, 1, 3, 3, 1, 1, 1]),
+            ("C", &vec![0, -1, 3, 2, 0, 1, 0]),
+            ("Qm", &vec![1, 0, 0, 0, 0, 0, 0]),
+        ]
+    );
+    assert!(test
+        .get_all_chords()
+        .contains(&("Qm", &vec![1, 0, 0, 0, 0, 0, 0])));
+}
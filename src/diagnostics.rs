@@ -0,0 +1,195 @@
+use std::cell::{Cell, RefCell};
+use std::fmt;
+
+/// A position in a chopro source file.
+///
+/// The source is an index of a source name in `Diagnostics`, where 0
+/// is the main source (other sources are included files).
+/// Both line and column are 1-based, as in most editors.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pos {
+    pub source: usize,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Pos {
+    /// A position in the main source.
+    pub fn new(line: usize, col: usize) -> Pos {
+        Pos {
+            source: 0,
+            line,
+            col,
+        }
+    }
+    /// A position in a specific source.
+    pub fn in_source(source: usize, line: usize, col: usize) -> Pos {
+        Pos { source, line, col }
+    }
+    /// The position `n` characters to the right of this position.
+    pub fn right(self, n: usize) -> Pos {
+        Pos {
+            col: self.col + n,
+            ..self
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        out.write_str(match self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+/// Identifies the kind of problem a diagnostic is about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Code {
+    ReadError,
+    UnknownDirective,
+    BadChordDef,
+    WrongInstrument,
+    UnknownChord,
+    StrayEnd,
+    Unterminated,
+    BadColumns,
+    NoTitle,
+    DuplicateTitle,
+    NoChorus,
+    IncludeCycle,
+    BadTranspose,
+    BadCapo,
+}
+
+impl Code {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Code::ReadError => "read-error",
+            Code::UnknownDirective => "unknown-directive",
+            Code::BadChordDef => "bad-chord-def",
+            Code::WrongInstrument => "wrong-instrument",
+            Code::UnknownChord => "unknown-chord",
+            Code::StrayEnd => "stray-end",
+            Code::Unterminated => "unterminated",
+            Code::BadColumns => "bad-columns",
+            Code::NoTitle => "no-title",
+            Code::DuplicateTitle => "duplicate-title",
+            Code::NoChorus => "no-chorus",
+            Code::IncludeCycle => "include-cycle",
+            Code::BadTranspose => "bad-transpose",
+            Code::BadCapo => "bad-capo",
+        }
+    }
+}
+
+/// A single problem found in a chopro source.
+#[derive(Debug)]
+pub struct Diagnostic<'a> {
+    pub source: &'a str,
+    pub pos: Pos,
+    pub severity: Severity,
+    pub code: Code,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic<'_> {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            out,
+            "{}:{}:{}: {}[{}]: {}",
+            self.source,
+            self.pos.line,
+            self.pos.col,
+            self.severity,
+            self.code.as_str(),
+            self.message,
+        )
+    }
+}
+
+/// Reports diagnostics for one chopro source (and the files it
+/// includes) to stderr.
+///
+/// Keeps count of the reported warnings and errors.
+pub struct Diagnostics {
+    sources: RefCell<Vec<String>>,
+    warnings: Cell<usize>,
+    errors: Cell<usize>,
+}
+
+impl Diagnostics {
+    pub fn new(source: &str) -> Diagnostics {
+        Diagnostics {
+            sources: RefCell::new(vec![source.to_string()]),
+            warnings: Cell::new(0),
+            errors: Cell::new(0),
+        }
+    }
+
+    /// Add the name of an included source, returning its index.
+    pub fn add_source(&self, name: &str) -> usize {
+        let mut sources = self.sources.borrow_mut();
+        sources.push(name.to_string());
+        sources.len() - 1
+    }
+
+    pub fn warning(&self, pos: Pos, code: Code, message: impl fmt::Display) {
+        self.warnings.set(self.warnings.get() + 1);
+        self.report(pos, Severity::Warning, code, message);
+    }
+
+    pub fn error(&self, pos: Pos, code: Code, message: impl fmt::Display) {
+        self.errors.set(self.errors.get() + 1);
+        self.report(pos, Severity::Error, code, message);
+    }
+
+    fn report(
+        &self,
+        pos: Pos,
+        severity: Severity,
+        code: Code,
+        message: impl fmt::Display,
+    ) {
+        let sources = self.sources.borrow();
+        let diagnostic = Diagnostic {
+            source: &sources[pos.source],
+            pos,
+            severity,
+            code,
+            message: message.to_string(),
+        };
+        eprintln!("{diagnostic}");
+    }
+
+    pub fn n_warnings(&self) -> usize {
+        self.warnings.get()
+    }
+
+    pub fn n_errors(&self) -> usize {
+        self.errors.get()
+    }
+}
+
+#[test]
+fn test_format_diagnostic() {
+    let diagnostic = Diagnostic {
+        source: "songs/yesterday.chopro",
+        pos: Pos::new(12, 3),
+        severity: Severity::Warning,
+        code: Code::UnknownChord,
+        message: "Unknown chord Smaj9".into(),
+    };
+    assert_eq!(
+        diagnostic.to_string(),
+        "songs/yesterday.chopro:12:3: warning[unknown-chord]: \
+         Unknown chord Smaj9",
+    );
+}
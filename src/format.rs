@@ -0,0 +1,228 @@
+//! Write chopro source in a normalized form.
+use crate::diagnostics::Diagnostics;
+use crate::parser::{ChoproParser, ChordFileExpression, SectionKind, Token};
+use std::path::Path;
+
+/// Format a chopro source in normalized form.
+///
+/// Directives are written with their long names, one per line, with
+/// arguments trimmed and chord definitions spaced consistently.
+/// Comments, includes and selectors are kept as written, as are lines
+/// that mix a directive with other text and chord definitions with
+/// `keys`, since they can not be normalized without losing content.
+pub fn format_source(
+    source: &str,
+    path: &Path,
+    diagnostics: &Diagnostics,
+) -> String {
+    let parser =
+        ChoproParser::new(source.as_bytes(), path, diagnostics, &[]).verbatim();
+    let mut out = String::new();
+    for token in parser {
+        format_token(&mut out, &token);
+    }
+    out
+}
+
+/// Write a token, and the lines of a chorus or section, to `out`.
+pub fn format_token(out: &mut String, token: &Token) {
+    let sel = token.selector.as_deref();
+    match &token.expr {
+        ChordFileExpression::Title { s } => directive(out, "title", sel, s),
+        ChordFileExpression::SubTitle { s } => {
+            directive(out, "subtitle", sel, s)
+        }
+        ChordFileExpression::Comment { s } => directive(out, "comment", sel, s),
+        ChordFileExpression::Meta { name, value } => match &**name {
+            "artist" | "composer" | "lyricist" | "arranger" | "album"
+            | "year" | "key" | "capo" | "tempo" | "time" | "duration"
+            | "copyright" => directive(out, name, sel, value),
+            name => {
+                let arg = format!("{name} {}", value.trim());
+                directive(out, "meta", sel, &arg)
+            }
+        },
+        ChordFileExpression::ChordDef { name, def, fingers } => {
+            let mut arg = format!("{name} base-fret {} frets", def[0]);
+            for fret in &def[1..] {
+                match fret {
+                    -1 => arg.push_str(" x"),
+                    fret => arg.push_str(&format!(" {fret}")),
+                }
+            }
+            if !fingers.is_empty() {
+                arg.push_str(" fingers");
+                for finger in fingers {
+                    arg.push_str(&format!(" {finger}"));
+                }
+            }
+            directive(out, "define", sel, &arg)
+        }
+        ChordFileExpression::Chorus { label, lines } => {
+            let label = label.as_deref().unwrap_or_default();
+            directive(out, "start_of_chorus", sel, label);
+            for line in lines {
+                format_token(out, line);
+            }
+            directive(out, "end_of_chorus", sel, "");
+        }
+        ChordFileExpression::EndOfChorus => {
+            directive(out, "end_of_chorus", sel, "")
+        }
+        ChordFileExpression::ChorusRecall { label } => {
+            let label = label.as_deref().unwrap_or_default();
+            directive(out, "chorus", sel, label)
+        }
+        ChordFileExpression::Section { kind, label, lines } => {
+            let label = label.as_deref().unwrap_or_default();
+            directive(out, &section_directive("start", kind), sel, label);
+            for line in lines {
+                format_token(out, line);
+            }
+            directive(out, &section_directive("end", kind), sel, "");
+        }
+        ChordFileExpression::EndOfSection { kind } => {
+            directive(out, &section_directive("end", kind), sel, "")
+        }
+        ChordFileExpression::Tab { lines } => {
+            directive(out, "start_of_tab", sel, "");
+            for line in lines {
+                out.push_str(line.trim_end());
+                out.push('\n');
+            }
+            directive(out, "end_of_tab", sel, "");
+        }
+        ChordFileExpression::Form { name, keys, form } => {
+            let mut arg = name.trim().to_string();
+            for key in keys {
+                arg.push_str("//");
+                arg.push_str(key);
+            }
+            directive(out, "start_of_form", sel, &arg);
+            for line in form {
+                out.push_str(&line.join(" / "));
+                out.push('\n');
+            }
+            directive(out, "end_of_form", sel, "");
+        }
+        ChordFileExpression::EndOfTab => directive(out, "end_of_tab", sel, ""),
+        ChordFileExpression::StartColumns { n_columns } => {
+            directive(out, "columns", sel, &n_columns.to_string())
+        }
+        ChordFileExpression::Transpose { semitones } => {
+            directive(out, "transpose", sel, &semitones.to_string())
+        }
+        ChordFileExpression::ColumnBreak => {
+            directive(out, "column_break", sel, "")
+        }
+        ChordFileExpression::PageBreak => directive(out, "page_break", sel, ""),
+        ChordFileExpression::NewSong => directive(out, "new_song", sel, ""),
+        ChordFileExpression::Line { segments } => {
+            let mut line = String::new();
+            for segment in segments {
+                if let Some(chord) = &segment.chord {
+                    line.push('[');
+                    line.push_str(chord);
+                    line.push(']');
+                }
+                line.push_str(&segment.lyrics);
+            }
+            out.push_str(line.trim_end());
+            out.push('\n');
+        }
+        ChordFileExpression::Unknown { s }
+        | ChordFileExpression::Verbatim { s } => {
+            out.push_str(s.trim_end());
+            out.push('\n');
+        }
+        ChordFileExpression::Include { name } => {
+            directive(out, "include", sel, name)
+        }
+    }
+}
+
+/// Write a directive line, e.g. `{title: Yesterday}`.
+fn directive(out: &mut String, name: &str, sel: Option<&str>, arg: &str) {
+    out.push('{');
+    out.push_str(name);
+    if let Some(sel) = sel {
+        out.push('-');
+        out.push_str(sel);
+    }
+    let arg = arg.trim();
+    if !arg.is_empty() {
+        out.push_str(": ");
+        out.push_str(arg);
+    }
+    out.push_str("}\n");
+}
+
+fn section_directive(start_or_end: &str, kind: &SectionKind) -> String {
+    format!("{start_or_end}_of_{}", kind.name())
+}
+
+#[test]
+fn test_format_source() {
+    let source = "{t:Yesterday }\n\
+                  {st: Beatles}\n\
+                  # A comment\n\
+                  {define-guitar:  C/G base-fret 0 frets 3 3  2 0 1 0}\n\
+                  {define: F#m frets 2 4 4 2 2 2 fingers 1 3 4 1 1 1}\n\
+                  {meta: Arranger Someone}\n\
+                  {meta: mood sad}\n\
+                  [G]Yesterday[F#m], all my   \n\
+                  \n\
+                  {soc: Chorus 2}\n\
+                  Why she [Em]had to go\n\
+                  {eoc}\n\
+                  {sov}\n\
+                  la\n\
+                  {eov}\n\
+                  {sot}\n\
+                  e|--0--|  \n\
+                  {eot}\n\
+                  {col: 2}\n\
+                  {transpose: -2 }\n\
+                  {colb}\n\
+                  {np}\n\
+                  {textfont: Times}\n\
+                  {include: intro.chopro}\n\
+                  [G]Hello {sic} my [C]friend  \n\
+                  {c: note} trailing text\n\
+                  {define: C frets x 3 2 0 1 0 keys 0 4 7}\n";
+    let expected = "{title: Yesterday}\n\
+                    {subtitle: Beatles}\n\
+                    # A comment\n\
+                    {define-guitar: C/G base-fret 0 frets 3 3 2 0 1 0}\n\
+                    {define: F#m base-fret 1 frets 2 4 4 2 2 2 \
+                    fingers 1 3 4 1 1 1}\n\
+                    {arranger: Someone}\n\
+                    {meta: mood sad}\n\
+                    [G]Yesterday[F#m], all my\n\
+                    \n\
+                    {start_of_chorus: Chorus 2}\n\
+                    Why she [Em]had to go\n\
+                    {end_of_chorus}\n\
+                    {start_of_verse}\n\
+                    la\n\
+                    {end_of_verse}\n\
+                    {start_of_tab}\n\
+                    e|--0--|\n\
+                    {end_of_tab}\n\
+                    {columns: 2}\n\
+                    {transpose: -2}\n\
+                    {column_break}\n\
+                    {page_break}\n\
+                    {textfont: Times}\n\
+                    {include: intro.chopro}\n\
+                    [G]Hello {sic} my [C]friend\n\
+                    {c: note} trailing text\n\
+                    {define: C frets x 3 2 0 1 0 keys 0 4 7}\n";
+    let diagnostics = Diagnostics::new("test");
+    let formatted = format_source(source, Path::new("test"), &diagnostics);
+    assert_eq!(formatted, expected);
+    assert_eq!(
+        format_source(&formatted, Path::new("test"), &diagnostics),
+        expected,
+    );
+}
@@ -1,4 +1,4 @@
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
 
 /// A key to transpose to and from
 pub struct Key {
@@ -29,7 +29,12 @@ impl Key {
             "F#" | "Gb" => 9,
             "G" => 10,
             "G#" | "Ab" => 11,
-            _ => todo!("Unkown key {key:?}"),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Unknown key {key:?}"),
+                ))
+            }
         };
         let noteset = if key.contains('b') || !maj && !key.contains('#') {
             0
@@ -42,6 +47,7 @@ impl Key {
             notes: &NOTE[noteset],
         })
     }
+    #[allow(clippy::wrong_self_convention)]
     pub fn from_nashville(&self, chord: &str) -> String {
         let (n, m) = parse_nashville(chord, self.maj);
         let note = self.notes[usize::from((self.base + n) % 12)];
@@ -0,0 +1,25 @@
+//! Create pdf songbooks from chopro source.
+//!
+//! A chopro source is read by a [`ChoproParser`], and the resulting
+//! tokens are collected into [`Song`]s.  Songs can then be rendered to
+//! pdf by the [`render`] module, checked for problems by the [`check`]
+//! module, or written back in normalized form by the [`format`] module.
+//! The chopro format is described in `chopro.md`.
+pub mod check;
+pub mod chord;
+pub mod chorddb;
+pub mod chords;
+pub mod diagnostics;
+pub mod format;
+pub mod key;
+pub mod metadata;
+pub mod pagedim;
+pub mod parser;
+pub mod render;
+pub mod song;
+pub mod voicing;
+
+pub use crate::chords::{ChordHolder, Instrument};
+pub use crate::key::Key;
+pub use crate::parser::ChoproParser;
+pub use crate::song::Song;
@@ -1,33 +1,51 @@
-mod chords;
-mod key;
-mod pagedim;
-
-use crate::chords::{ChordHolder, Instrument};
-use crate::key::Key;
-use crate::pagedim::PageDim;
-use clap::Parser;
-use pdf_canvas::graphicsstate::Color;
-use pdf_canvas::{BuiltinFont, Canvas, Pdf, TextObject};
-use regex::Regex;
-use std::fs::File;
-use std::io::{self, BufRead};
+use chord3::check::check_source;
+use chord3::chorddb;
+use chord3::chords::{Instrument, Tuning};
+use chord3::diagnostics::{Diagnostics, Pos};
+use chord3::format::{format_source, format_token};
+use chord3::pagedim::{parse_length, Margins, PageDim, Paper};
+use chord3::parser::{ChordFileExpression, Token};
+use chord3::render::{
+    render_appendix, render_chordlist, render_source, CapoChords, ChorusRecall,
+    Diagrams, Fit, RenderOptions,
+};
+use chord3::song::ChordDef;
+use clap::{Parser, Subcommand};
+use pdf_canvas::Pdf;
+use std::fs::{read_to_string, File};
+use std::io::{self, Read};
+use std::path::Path;
 use std::process::exit;
-use std::sync::Mutex;
 
 #[derive(Parser)]
 #[command(
     about,
     author,
     version,
-    mut_arg("input", |i| i.required_unless_present("chords")),
+    args_conflicts_with_subcommands = true,
+    args_override_self = true,
     after_help =
-        "At least one INPUT file is required unless the --chords flag is \
-         given.\n\n\
+        "If no INPUT file is given (and the --chords flag is not given), \
+         a chopro source is read from standard input.  An INPUT of - \
+         also means standard input.\n\n\
          Each INPUT file contains one or more song in the chopro format, \
          which is described at \
          https://github.com/kaj/chord3/blob/master/chopro.md ."
 )]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Read default options from this file.
+    ///
+    /// Each line of the file is a long option, optionally followed by
+    /// its value, e.g. `--paper a5` or `--gutter 15mm`.  Empty lines
+    /// and lines starting with `#` are ignored.  Options given on the
+    /// command line take precedence over options in the file.  The
+    /// file is not used by subcommands.
+    #[arg(long, value_name = "FILE", global = true)]
+    config: Option<String>,
+
     /// Title (in metadata) of the output PDF file.
     #[arg(long, default_value = "Songbook")]
     title: String,
@@ -40,10 +58,49 @@ struct Args {
     #[arg(long, value_enum, default_value_t = Instrument::Guitar)]
     instrument: Instrument,
 
+    /// Tuning of the instrument, for other tunings or instruments.
+    ///
+    /// Either comma-separated notes from the lowest string, e.g.
+    /// `D,A,D,G,A,D`, or one of the presets guitar, drop-d, dadgad,
+    /// open-d, open-g, banjo, tenor-guitar, bass, mandolin or ukulele.
+    /// The name of a preset is also used as a directive selector.
+    #[arg(long)]
+    tuning: Option<Tuning>,
+
+    /// Also use directives with this selector suffix.
+    ///
+    /// Directives with a selector, such as `{comment-vocals: ...}`,
+    /// are used only if the selector is the name of the instrument
+    /// or given by this option.  May be given multiple times.
+    #[arg(long, value_name = "SELECTOR")]
+    select: Vec<String>,
+
     /// Add a separate page of chord definitions.
     #[arg(long)]
     chords: bool,
 
+    /// Where to place the chord diagrams of each song.
+    ///
+    /// With `appendix`, the diagrams are not shown in the songs, but
+    /// the chords of all songs are collected in an appendix at the end.
+    #[arg(long, value_enum, default_value_t = Diagrams::Bottom)]
+    diagrams: Diagrams,
+
+    /// Load chord definitions from this file.
+    ///
+    /// The file is either a chopro file with `{define}` directives, or
+    /// has the argument of a `{define}` on each line, optionally
+    /// prefixed by a selector and a colon, e.g. `ukulele: Am frets 2 0
+    /// 0 0`.  The definitions are used on top of the built-in chords.
+    /// May be given multiple times, later files take precedence.
+    #[arg(long, value_name = "FILE")]
+    chord_db: Vec<String>,
+
+    /// Print all known chords for the instrument (including those from
+    /// chord db files) as `{define}` directives, and exit.
+    #[arg(long)]
+    print_chords: bool,
+
     /// Output PDF file name.
     #[arg(short, long, default_value = "chords.pdf")]
     output: String,
@@ -52,19 +109,165 @@ struct Args {
     #[arg(long)]
     sourcenames: bool,
 
+    /// Check the input for problems, without writing any PDF file.
+    ///
+    /// Exits with an error status if any problem is found.
+    #[arg(long)]
+    check: bool,
+
     /// Base font size, in points (72 points = 1 inch).
-    #[arg(long, default_value = "12")]
-    base_size: f32,
+    ///
+    /// Defaults to 12 for A4 and larger paper, and proportionally
+    /// smaller for smaller paper.
+    #[arg(long)]
+    base_size: Option<f32>,
+
+    /// Decrease the base size of songs that don't fit on one page.
+    ///
+    /// Each song is rendered with the largest base size (down to
+    /// --min-size) for which it fits, including its chord boxes, on a
+    /// page (or on a pair of facing pages, if it starts on a verso
+    /// page).  Songs that don't fit anyway use the base size.
+    #[arg(long, value_enum, default_value_t = Fit::None)]
+    fit: Fit,
+
+    /// The smallest base size to use with --fit.
+    #[arg(long, default_value = "8")]
+    min_size: f32,
+
+    /// The largest number of columns to use with --fit.
+    ///
+    /// If a song does not fit at any base size, more columns are
+    /// tried, up to this number.
+    #[arg(long, default_value = "1")]
+    fit_columns: u8,
+
+    /// Template for a line of song metadata under the subtitle.
+    ///
+    /// The template consists of parts separated by `|`, where each
+    /// part can refer to song metadata as `%{name}`.  Parts that
+    /// refer to metadata not given in the song are omitted.
+    /// Use an empty template to disable the metadata line.
+    #[arg(
+        long,
+        default_value = "Key: %{key}|Capo %{capo}|%{tempo} bpm|%{time}"
+    )]
+    header: String,
+
+    /// Transpose all songs by this number of semitones.
+    ///
+    /// This is added to any transposition given in the songs by the
+    /// `{transpose}` directive.
+    #[arg(long, default_value_t = 0, allow_hyphen_values = true)]
+    transpose: i8,
+
+    /// How to render a `{chorus}` directive.
+    #[arg(long, value_enum, default_value_t = ChorusRecall::Reference)]
+    chorus_recall: ChorusRecall,
+
+    /// How to name chords in songs with a `{capo}`.
+    ///
+    /// Chords in songs are written as the shapes played relative to
+    /// the capo, and chord boxes always show those shapes.
+    #[arg(long, value_enum, default_value_t = CapoChords::Shapes)]
+    capo_chords: CapoChords,
 
     #[clap(flatten)]
     page: PageArgs,
 
-    /// Chopro file(s) to parse.
+    /// Chopro file(s) to parse, or - for standard input.
+    input: Vec<String>,
+}
+
+impl Args {
+    fn render_options(&self) -> RenderOptions {
+        RenderOptions {
+            show_sourcename: self.sourcenames,
+            instrument: self.instrument,
+            tuning: self.tuning.clone(),
+            base_size: self.base_size.unwrap_or(12. * self.page.paper.scale()),
+            header: self.header.clone(),
+            selectors: self.selectors(),
+            chorus_recall: self.chorus_recall,
+            capo_chords: self.capo_chords,
+            transpose: self.transpose,
+            chord_db: self.chord_db(),
+            fit: self.fit,
+            min_size: self.min_size,
+            fit_columns: self.fit_columns,
+            diagrams: self.diagrams,
+        }
+    }
+
+    /// Load the chord db files.  Exits on failure to read a file.
+    fn chord_db(&self) -> Vec<ChordDef> {
+        let selectors = self.selectors();
+        let mut defs = vec![];
+        for name in &self.chord_db {
+            let diagnostics = Diagnostics::new(name);
+            match chorddb::load(Path::new(name), &diagnostics, &selectors) {
+                Ok(loaded) => defs.extend(loaded),
+                Err(e) => {
+                    eprintln!("Failed to read chord db {name}: {e}");
+                    exit(1);
+                }
+            }
+        }
+        defs
+    }
+
+    /// The active selectors for directives.
+    fn selectors(&self) -> Vec<String> {
+        let mut selectors = vec![self.instrument.name().to_string()];
+        if let Some(name) = self.tuning.as_ref().and_then(|t| t.name()) {
+            selectors.push(name.to_string());
+        }
+        selectors.extend(self.select.iter().cloned());
+        selectors
+    }
+
+    /// The input sources, standard input if no input file is given.
+    fn inputs(&self) -> Vec<String> {
+        if self.input.is_empty() && !self.chords {
+            vec!["-".into()]
+        } else {
+            self.input.clone()
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Rewrite chopro files in a normalized form.
+    ///
+    /// Directives are written with their long names, and whitespace
+    /// is normalized.  Files with errors are not rewritten.
+    Fmt(FmtArgs),
+}
+
+#[derive(Parser)]
+struct FmtArgs {
+    /// Don't rewrite any file, but exit with an error status if any
+    /// file is not formatted.
+    #[arg(long)]
+    check: bool,
+
+    /// Chopro file(s) to format.  Standard input (or -) is formatted
+    /// to standard output.
     input: Vec<String>,
 }
 
 #[derive(Parser)]
 struct PageArgs {
+    /// Paper size for the output.
+    ///
+    /// Either a name (A4, A5, B5, Letter or Legal) or a custom size
+    /// as WIDTHxHEIGHT in mm, cm, in or pt (the default), e.g.
+    /// `148x210mm` or `5.5x8.5in`.  Margins are scaled down for paper
+    /// smaller than A4.
+    #[arg(long, default_value = "A4")]
+    paper: Paper,
+
     /// Use landscape orientation for the output.
     #[arg(long)]
     landscape: bool,
@@ -82,307 +285,148 @@ struct PageArgs {
     /// Useful e.g. when writing pages to be included in a larger document.
     #[arg(long)]
     no_pageno: bool,
-}
 
-fn chordbox(
-    c: &mut Canvas<'_>,
-    left: f32,
-    top: f32,
-    name: &str,
-    strings: &[i8],
-    base_size: f32,
-) -> io::Result<()> {
-    let n_strings = (strings.len() - 1) as u8;
-    let n_bands: u8 = if n_strings == 4 { 8 } else { 4 };
-
-    let (dx, dy) = if n_strings == 4 {
-        (0.458_333_34 * base_size, 0.458_333_34 * base_size)
-    } else {
-        (0.416_666_66 * base_size, 0.583_333_3 * base_size)
-    };
-    let right = left + f32::from(n_strings - 1) * dx;
-    let bottom = top - (f32::from(n_bands) + 0.4) * dy;
-    let radius = 1.4;
-    c.center_text(
-        (left + right) / 2.0,
-        top + 2.0 + 4.0 * radius,
-        BuiltinFont::Helvetica_Oblique,
-        12.0,
-        name,
-    )?;
-    let barre = strings[0];
-    let up = if barre < 2 {
-        c.set_line_width(1.0)?;
-        c.line(left - 0.15, top + 0.5, right + 0.15, top + 0.5)?;
-        c.stroke()?;
-        for mark in &[5_u8, 7, 10] {
-            if n_bands >= *mark {
-                c.right_text(
-                    left - 0.4 * dx,
-                    top - (f32::from(*mark) - 0.1) * dy,
-                    BuiltinFont::Helvetica,
-                    dy,
-                    &format!("{mark}"),
-                )?;
-            }
-        }
-        0.0
-    } else {
-        c.right_text(
-            left - 0.4 * dx,
-            top - 0.9 * dy,
-            BuiltinFont::Helvetica,
-            dy,
-            &format!("{barre}"),
-        )?;
-        1.6
-    };
-    c.set_line_width(0.3)?;
-    for b in 0..=n_bands {
-        let y = top - f32::from(b) * dy;
-        c.line(left, y, right, y)?;
-    }
-    for s in 0..n_strings {
-        let x = left + f32::from(s) * dx;
-        c.line(x, top + up, x, bottom)?;
-    }
-    c.stroke()?;
-    let radius = base_size * 0.11;
-    let above = top + 2.0 + radius;
-    for (string, band) in strings[1..].iter().enumerate() {
-        let x = left + string as f32 * dx;
-        match *band {
-            -2 => (), // No-op for unknown chord
-            -1 => {
-                let (xl, xr) = (x - radius, x + radius);
-                let (yt, yb) = (above - radius, above + radius);
-                c.line(xl, yt, xr, yb)?;
-                c.line(xr, yt, xl, yb)?;
-                c.stroke()?;
-            }
-            0 => {
-                c.circle(x, above, radius)?;
-                c.stroke()?;
-            }
-            band => {
-                let y = top - (f32::from(band) - 0.5) * dy;
-                c.circle(x, y, radius * 1.2)?;
-                c.fill()?;
-            }
-        }
-    }
-    Ok(())
-}
+    /// Top margin, in mm, cm, in or pt (the default).
+    ///
+    /// Defaults to 20pt, scaled down for paper smaller than A4, as
+    /// are the other margins.
+    #[arg(long, value_name = "LENGTH", value_parser = parse_length)]
+    margin_top: Option<f32>,
 
-enum ChordFileExpression {
-    Title { s: String },
-    SubTitle { s: String },
-    Comment { s: String },
-    ChordDef { name: String, def: Vec<i8> },
-    Chorus { lines: Vec<ChordFileExpression> },
-    EndOfChorus,
-    Tab { lines: Vec<String> },
-    Form {
-        name: String,
-        keys: Vec<String>,
-        form: Vec<Vec<String>>,
-    },
-    EndOfTab,
-    StartColumns { n_columns: u8 },
-    ColumnBreak,
-    PageBreak,
-    NewSong,
-    Line { s: Vec<String> },
-}
+    /// Bottom margin, to the baseline of the page footer.
+    ///
+    /// Defaults to 20pt.
+    #[arg(long, value_name = "LENGTH", value_parser = parse_length)]
+    margin_bottom: Option<f32>,
+
+    /// Inner margin, on the spine side of pages, not including the
+    /// gutter.
+    ///
+    /// Defaults to 20pt.
+    #[arg(long, value_name = "LENGTH", value_parser = parse_length)]
+    margin_inner: Option<f32>,
+
+    /// Outer margin, on the other side from the spine.
+    ///
+    /// Defaults to 20pt.
+    #[arg(long, value_name = "LENGTH", value_parser = parse_length)]
+    margin_outer: Option<f32>,
 
-struct ChoproParser<R: io::Read> {
-    source: Mutex<io::Lines<io::BufReader<R>>>,
-    eof: bool,
+    /// Extra inner margin for the binding, e.g. larger for ring
+    /// binders than for spiral binding.
+    ///
+    /// Defaults to 50pt.
+    #[arg(long, value_name = "LENGTH", value_parser = parse_length)]
+    gutter: Option<f32>,
 }
 
-impl ChoproParser<File> {
-    fn open(path: &str) -> io::Result<ChoproParser<File>> {
-        let f = File::open(path)?;
-        Ok(ChoproParser::new(f))
+impl PageArgs {
+    /// The default margins for the paper, with any given margin.
+    fn margins(&self) -> Margins {
+        let default = Margins::for_paper(self.paper);
+        Margins {
+            top: self.margin_top.unwrap_or(default.top),
+            bottom: self.margin_bottom.unwrap_or(default.bottom),
+            inner: self.margin_inner.unwrap_or(default.inner),
+            outer: self.margin_outer.unwrap_or(default.outer),
+            gutter: self.gutter.unwrap_or(default.gutter),
+        }
     }
 }
-impl<R: io::Read> ChoproParser<R> {
-    fn new(source: R) -> ChoproParser<R> {
-        let reader = io::BufReader::new(source);
-        ChoproParser {
-            source: Mutex::new(reader.lines()),
-            eof: false,
-        }
+
+impl From<&PageArgs> for PageDim {
+    fn from(args: &PageArgs) -> Self {
+        PageDim::new(
+            args.paper,
+            args.landscape,
+            !args.no_duplex,
+            !args.no_pageno,
+        )
+        .with_margins(args.margins())
     }
+}
 
-    // Internal: Return the next line that is not a comment
-    fn nextline(&mut self) -> Option<String> {
-        loop {
-            match self.source.lock().unwrap().next() {
-                Some(Ok(line)) => {
-                    let comment_re = Regex::new(r"^\s*#").unwrap();
-                    if !comment_re.is_match(&line) {
-                        return Some(line);
-                    }
-                }
-                Some(Err(e)) => {
-                    println!("Failed to read source: {e}");
-                    self.eof = true;
-                    return None;
-                }
-                _ => {
-                    self.eof = true;
-                    return None;
-                }
-            }
+/// Parse the command line arguments.
+///
+/// Unless a subcommand is given, the options from a `--config` file
+/// (if any) are inserted first, so that the command line takes
+/// precedence.  Exits on failure to read the file.
+fn parse_args(mut args: Vec<String>) -> Args {
+    let parsed = Args::parse_from(&args);
+    match (&parsed.command, &parsed.config) {
+        (None, Some(path)) => {
+            args.splice(1..1, read_config(path));
+            Args::parse_from(args)
         }
+        _ => parsed,
     }
+}
 
-    fn is_eof(&self) -> bool {
-        self.eof
-    }
+/// Read the options from a `--config` file.
+fn read_config(path: &str) -> Vec<String> {
+    let config = read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("Failed to read config {path}: {e}");
+        exit(1);
+    });
+    config
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .flat_map(|line| match line.split_once(char::is_whitespace) {
+            Some((option, value)) => vec![option, value.trim()],
+            None => vec![line],
+        })
+        .map(String::from)
+        .collect()
 }
 
-impl<R: io::Read> Iterator for ChoproParser<R> {
-    type Item = ChordFileExpression;
-
-    fn next(&mut self) -> Option<ChordFileExpression> {
-        if let Some(line) = self.nextline() {
-            let re =
-                Regex::new(r"\{(?P<cmd>\w+)(?::?\s*(?P<arg>.*))?\}").unwrap();
-            if let Some(caps) = re.captures(&line) {
-                let arg =
-                    caps.name("arg").map_or("", |m| m.as_str()).to_string();
-                match &*caps.name("cmd").unwrap().as_str().to_lowercase() {
-                    "title" | "t" => {
-                        Some(ChordFileExpression::Title { s: arg })
-                    }
-                    "subtitle" | "st" => {
-                        Some(ChordFileExpression::SubTitle { s: arg })
-                    }
-                    "comment" | "c" | "ci" | "cb" => {
-                        Some(ChordFileExpression::Comment { s: arg })
-                    }
-                    "define" => {
-                        //println!("Parse chord def '{}'", arg);
-                        let re = Regex::new(
-                            r"(?i)^([\S]+)\s+base-fret\s+([0-9]+)\s+frets(?:\s+([x0-9-]))(?:\s+([x0-5-]))(?:\s+([x0-5-]))(?:\s+([x0-5-]))(?:\s+([x0-5-]))?(?:\s+([x0-5-]))?\s*$").unwrap();
-                        if let Some(caps) = re.captures(&arg) {
-                            let mut caps = caps.iter().skip(1);
-                            let name: String = caps
-                                .next()
-                                .unwrap()
-                                .unwrap()
-                                .as_str()
-                                .to_string();
-                            let def = caps
-                                .flatten()
-                                .map(|cap| match cap.as_str() {
-                                    "x" | "X" | "-" => -1,
-                                    s => s.parse::<i8>().unwrap(),
-                                })
-                                .collect();
-                            Some(ChordFileExpression::ChordDef { name, def })
-                        } else {
-                            let whole = caps.get(0).unwrap().as_str();
-                            println!("Warning: Bad chord definition {whole}");
-                            Some(ChordFileExpression::Comment {
-                                s: whole.to_string(),
-                            })
-                        }
-                    }
-                    "soc" | "start_of_chorus" => {
-                        let mut lines = vec![];
-                        for line in self {
-                            match line {
-                                ChordFileExpression::EndOfChorus => {
-                                    break;
-                                }
-                                line => lines.push(line),
-                            }
-                        }
-                        Some(ChordFileExpression::Chorus { lines })
-                    }
-                    "eoc" | "end_of_chorus" => {
-                        Some(ChordFileExpression::EndOfChorus)
-                    }
-                    "sot" | "start_of_tab" => {
-                        let mut lines = vec![];
-                        let end =
-                            Regex::new(r"\{(eot|end_of_tab):?\s*").unwrap();
-                        while let Some(line) = self.nextline() {
-                            if end.is_match(&line) {
-                                break;
-                            }
-                            lines.push(line);
-                        }
-                        Some(ChordFileExpression::Tab { lines })
-                    }
-                    "eot" | "end_of_tab" => Some(ChordFileExpression::EndOfTab),
-                    "sof" | "start_of_form" => {
-                        let mut arg = arg.split("//");
-                        let mut form = vec![];
-                        let end =
-                            Regex::new(r"\{e(of|nd_of_form):?\s*").unwrap();
-                        while let Some(line) = self.nextline() {
-                            if end.is_match(&line) {
-                                break;
-                            }
-                            form.push(
-                                line.split('/')
-                                    .map(str::trim)
-                                    .map(Into::into)
-                                    .collect::<Vec<String>>(),
-                            );
-                        }
-                        Some(ChordFileExpression::Form {
-                            name: arg.next().unwrap_or_default().into(),
-                            keys: arg.map(|s| s.trim().into()).collect(),
-                            form,
-                        })
-                    }
-                    "eof" | "end_of_form" => {
-                        Some(ChordFileExpression::EndOfTab)
-                    }
-
-                    "columns" | "col" => {
-                        Some(ChordFileExpression::StartColumns {
-                            n_columns: arg.parse::<u8>().unwrap(),
-                        })
-                    }
-                    "colb" => Some(ChordFileExpression::ColumnBreak),
-                    "page_break" | "np" => Some(ChordFileExpression::PageBreak),
-                    "new_song" => Some(ChordFileExpression::NewSong),
-                    x => {
-                        println!("unknown expression {x}");
-                        Some(ChordFileExpression::Comment {
-                            s: caps.get(0).unwrap().as_str().to_string(),
-                        })
-                    }
-                }
-            } else {
-                let mut s = vec![];
-                let re = Regex::new(r"([^\[]*)(?:\[([^\]]*)\])?").unwrap();
-                for caps in re.captures_iter(&line.replace('\t', "    ")) {
-                    s.push(caps.get(1).unwrap().as_str().to_string());
-                    if let Some(chord) = caps.get(2) {
-                        s.push(chord.as_str().to_string());
-                    }
-                }
-                Some(ChordFileExpression::Line { s })
-            }
-        } else {
-            None
+#[test]
+fn test_parse_args_config() {
+    let dir = std::env::temp_dir()
+        .join(format!("chord3-test-config-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("chord3.conf");
+    std::fs::write(&config, "# A config\n--title Book\n--paper a5\n").unwrap();
+    let config = config.to_str().unwrap();
+    let parse = |args: &[&str]| {
+        parse_args(args.iter().map(|s| s.to_string()).collect())
+    };
+
+    let args = parse(&["chord3", "--config", config]);
+    assert_eq!(args.title, "Book");
+    let args = parse(&["chord3", "--config", config, "--title", "Cli"]);
+    assert_eq!(args.title, "Cli");
+
+    let args = parse(&["chord3", "fmt", "--check", "--config", config, "a"]);
+    match args.command {
+        Some(Command::Fmt(fmt)) => {
+            assert!(fmt.check);
+            assert_eq!(fmt.input, ["a"]);
         }
+        None => panic!("Expected the fmt command"),
     }
+    assert_eq!(args.title, "Songbook");
+    std::fs::remove_dir_all(&dir).unwrap();
 }
 
 fn main() {
-    let args = Args::parse();
+    let args = parse_args(std::env::args().collect());
+    if let Some(Command::Fmt(fmt)) = &args.command {
+        format_files(fmt);
+        return;
+    }
+    if args.check {
+        check_songs(&args);
+        return;
+    }
+    if args.print_chords {
+        print_chords(&args);
+        return;
+    }
     let filename = &args.output;
     let mut document = Pdf::create(filename)
         .map_err(|err| {
-            println!("Failed to open {filename}: {err}");
+            eprintln!("Failed to open {filename}: {err}");
             exit(1);
         })
         .unwrap();
@@ -396,422 +440,149 @@ fn main() {
         "\nhttps://github.com/kaj/chord3"
     ));
 
-    let show_sourcenames = args.sourcenames;
-    let instrument = args.instrument;
-    let base_size = args.base_size;
-    let mut page = PageDim::from(args.page);
-
-    for name in &args.input {
-        match render_song(
-            &mut document,
-            name,
-            show_sourcenames,
-            page,
-            instrument,
-            base_size,
-        ) {
-            Ok(p) => page = p.next(),
-            Err(e) => println!("Failed to handle {name}: {e}"),
+    let options = args.render_options();
+    let input = args.inputs();
+    let mut page = PageDim::from(&args.page);
+    let mut appendix = options.chords();
+
+    for name in &input {
+        let result = open_input(name).and_then(|source| {
+            render_source(
+                &mut document,
+                source,
+                source_name(name),
+                page,
+                &options,
+                &mut appendix,
+            )
+        });
+        match result {
+            Ok(Some(p)) => page = p.next(),
+            Ok(None) => (),
+            Err(e) => eprintln!("Failed to handle {name}: {e}"),
+        }
+    }
+    if args.diagrams == Diagrams::Appendix {
+        if let Some(last) =
+            render_appendix(&mut document, page, &appendix, &options)
+                .expect("Render appendix")
+        {
+            page = last.next();
         }
     }
     if args.chords {
-        render_chordlist(&mut document, page, instrument, base_size)
+        render_chordlist(&mut document, page, &options)
             .expect("Render chordlist");
     }
     document.finish().unwrap();
 }
 
-fn render_chordlist(
-    document: &mut Pdf,
-    page: PageDim,
-    instrument: Instrument,
-    base_size: f32,
-) -> io::Result<()> {
-    let chords = ChordHolder::new_for(instrument);
-
-    document.render_page(page.width(), page.height(), |c| {
-        let s = "Chords";
-        c.add_outline(s);
-        c.left_text(
-            page.left(),
-            page.top() - 1.5 * base_size,
-            BuiltinFont::Times_Bold,
-            base_size * 4. / 3.,
-            s,
-        )?;
-        render_chordboxes(c, page, chords.get_all_chords(), base_size)
-    })
+fn open_input(name: &str) -> io::Result<Box<dyn io::Read>> {
+    if name == "-" {
+        Ok(Box::new(io::stdin().lock()))
+    } else {
+        Ok(Box::new(File::open(name)?))
+    }
 }
 
-fn render_song(
-    document: &mut Pdf,
-    songfilename: &str,
-    show_sourcename: bool,
-    page: PageDim,
-    instrument: Instrument,
-    base_size: f32,
-) -> io::Result<PageDim> {
-    let mut source = ChoproParser::open(songfilename)?;
-    let mut chords = ChordHolder::new_for(instrument);
-    let mut page = page;
-    let mut column_top = page.top();
-    let mut left = page.left();
-    let mut n_cols = 1;
-    while !source.is_eof() {
-        document.render_page(page.width(), page.height(), |c| {
-            let mut y = page.top();
-            if show_sourcename {
-                if page.is_verso() {
-                    c.right_text(
-                        page.right(),
-                        20.0,
-                        BuiltinFont::Helvetica_Oblique,
-                        10.0,
-                        songfilename,
-                    )?;
-                } else {
-                    c.left_text(
-                        page.left(),
-                        20.0,
-                        BuiltinFont::Helvetica_Oblique,
-                        10.0,
-                        songfilename,
-                    )?;
-                }
-            }
-            write_pageno(c, &page)?;
-            let mut box_w = 0.;
-            for token in source.by_ref() {
-                if let ChordFileExpression::StartColumns { n_columns } = token {
-                    column_top = y;
-                    n_cols = n_columns;
-                } else {
-                    y = render_token(
-                        token,
-                        y,
-                        left,
-                        c,
-                        &mut chords,
-                        base_size,
-                        &mut box_w,
-                    )?;
-                    if y == std::f32::NEG_INFINITY {
-                        render_chordboxes(
-                            c,
-                            page,
-                            chords.get_used(),
-                            base_size,
-                        )?;
-                        n_cols = 1;
-                        chords = ChordHolder::new_for(instrument);
-                        page = page.next();
-                        left = page.left();
-                        return Ok(());
-                    }
-                    if y < (2. + 4. * base_size) {
-                        left += page.inner_width() / f32::from(n_cols) + 10.0;
-                        if left < page.right() {
-                            y = column_top;
-                        } else {
-                            page = page.next();
-                            left = page.left();
-                            return Ok(());
-                        }
-                    }
-                }
-            }
-            render_chordboxes(c, page, chords.get_used(), base_size)?;
-            Ok(())
-        })?;
+fn source_name(name: &str) -> &str {
+    if name == "-" {
+        "<stdin>"
+    } else {
+        name
     }
-    Ok(page)
 }
 
-fn write_pageno(c: &mut Canvas, page: &PageDim) -> io::Result<()> {
-    if let Some(pageno) = page.pageno() {
-        let font = BuiltinFont::Times_Italic;
-        let pageno = format!("{pageno}");
-        if page.is_verso() {
-            c.left_text(page.left(), 20.0, font, 12.0, &pageno)?;
-        } else {
-            c.right_text(page.right(), 20.0, font, 12.0, &pageno)?;
-        }
+/// Print all known chords as `{define}` directives.
+fn print_chords(args: &Args) {
+    let chords = args.render_options().chords();
+    let mut out = String::new();
+    for (name, def) in chords.get_all_chords() {
+        let fingers = chords.get_fingers(name).unwrap_or_default();
+        let token = Token {
+            pos: Pos::new(0, 0),
+            expr: ChordFileExpression::ChordDef {
+                name: name.to_string(),
+                def: def.clone(),
+                fingers: fingers.to_vec(),
+            },
+            selector: None,
+        };
+        format_token(&mut out, &token);
     }
-    Ok(())
+    print!("{out}");
 }
 
-fn render_chordboxes(
-    c: &mut Canvas<'_>,
-    page: PageDim,
-    used_chords: Vec<(&str, &Vec<i8>)>,
-    base_size: f32,
-) -> io::Result<()> {
-    let (box_width, box_height) = match used_chords.first().map(|v| v.1.len()) {
-        Some(7) => (base_size * 3.5, base_size * 31. / 6.),
-        Some(5) => (base_size * 3., base_size * 19. / 3.),
-        Some(0) | None => return Ok(()),
-        x => {
-            println!("Warning: Unkown kind of chord, {x:?}");
-            return Ok(());
-        }
-    };
-    let n_chords = used_chords.len() as u32;
-    if n_chords > 0 {
-        let n_aside = (page.inner_width() / box_width) as u32;
-        let box_width = (page.inner_width() + 7.0) / n_aside as f32;
-        let n_height = (n_chords + n_aside - 1) / n_aside;
-        let n_first = n_chords - (n_height - 1) * n_aside;
-        let mut x = page.right() - n_first as f32 * box_width;
-        let mut y = 10.0 + n_height as f32 * box_height;
-        for (chord, chorddef) in used_chords {
-            chordbox(c, x + base_size * 1.25, y, chord, chorddef, base_size)?;
-            x += box_width;
-            if x >= page.right() {
-                x = page.right() - n_aside as f32 * box_width;
-                y -= box_height;
+/// Check all input songs, and exit with an error status if any
+/// problems are found.
+fn check_songs(args: &Args) {
+    let options = args.render_options();
+    let mut n_problems = 0;
+    for name in &args.inputs() {
+        let result = open_input(name).and_then(|source| {
+            check_source(source, source_name(name), &options)
+        });
+        match result {
+            Ok(n) => n_problems += n,
+            Err(e) => {
+                eprintln!("Failed to handle {name}: {e}");
+                n_problems += 1;
             }
         }
     }
-    Ok(())
+    if n_problems > 0 {
+        eprintln!("Found {n_problems} problem(s).");
+        exit(1);
+    }
 }
 
-fn render_token(
-    token: ChordFileExpression,
-    y: f32,
-    left: f32,
-    c: &mut Canvas<'_>,
-    chords: &mut ChordHolder,
-    base_size: f32,
-    box_w: &mut f32,
-) -> io::Result<f32> {
-    let times_italic = c.get_font(BuiltinFont::Times_Italic);
-    let times = c.get_font(BuiltinFont::Times_Roman);
-    let chordfont = c.get_font(BuiltinFont::Helvetica_Oblique);
-    let tabfont = c.get_font(BuiltinFont::Courier);
-
-    let chord_size = 0.82 * base_size;
-
-    match token {
-        ChordFileExpression::Title { s } => {
-            c.add_outline(&s);
-            let y = y - 1.5 * base_size;
-            c.left_text(
-                left,
-                y,
-                BuiltinFont::Times_Bold,
-                base_size * 4. / 3.,
-                &s,
-            )?;
-            Ok(y)
-        }
-        ChordFileExpression::SubTitle { s } => c.text(|t| {
-            let y = y - 16.0;
-            t.set_font(&times_italic, 14.0)?;
-            t.pos(left, y)?;
-            t.show(&s)?;
-            Ok(y)
-        }),
-        ChordFileExpression::Comment { s } => c.text(|t| {
-            let y = y - base_size;
-            t.set_font(&times_italic, base_size)?;
-            t.pos(left, y)?;
-            t.show(&s)?;
-            Ok(y)
-        }),
-        ChordFileExpression::ChordDef { name, def } => {
-            chords.define(name, def);
-            Ok(y)
-        }
-        ChordFileExpression::Chorus { lines } => {
-            let mut y2 = y;
-            for line in lines {
-                y2 = render_token(
-                    line,
-                    y2,
-                    left + 10.0,
-                    c,
-                    chords,
-                    base_size,
-                    box_w,
-                )?;
-            }
-            y2 -= 4.0;
-            c.set_line_width(0.5)?;
-            c.line(left - 6.0, y, left - 6.0, y2)?;
-            c.stroke()?;
-            Ok(y2)
-        }
-        ChordFileExpression::EndOfChorus => {
-            println!("Warning: Stray end of chorus in song!");
-            Ok(y)
-        }
-        ChordFileExpression::Tab { lines } => c.text(|t| {
-            let size = base_size / 1.2;
-            let mut y = y;
-            t.pos(left, y)?;
-            t.set_font(&tabfont, size)?;
-            t.set_leading(size)?;
-            for line in lines {
-                y -= size;
-                t.show_line(&line)?;
-            }
-            Ok(y)
-        }),
-        ChordFileExpression::Form { name, keys, form } => c.text(|t| {
-            t.gsave()?;
-            let mut y = y - base_size;
-            t.set_font(&times_italic, chord_size)?;
-            t.pos(left, y)?;
-            t.show(&name)?;
-
-            t.set_fill_color(Color::gray(96))?;
-            t.set_font(&chordfont, chord_size)?;
-            let leading = chord_size * 1.2;
-            // TODO: Needs more if multiple chords in same measure
-
-            let s_w = chordfont.get_width(chord_size, " ");
-            let sl_w = chordfont.get_width(chord_size, "/ ");
-            let key = keys.first().map(|k| Key::new(k))
-                .unwrap_or(Key::new("Bb"))?;
-            let measure_w = form
-                .iter()
-                .flat_map(|l| l.iter())
-                .map(|m| {
-                    m.split_ascii_whitespace()
-                        .map(|c| {
-                            let chord = &key.from_nashville(&c);
-                            chordfont.get_width(chord_size, chord)
-                                + s_w
-                        })
-                        .sum()
-                })
-                .fold(0_f32, |a, b| a.max(b))
-                + sl_w;
-
-            let n_measures = form.iter().map(|l| l.len()).max().unwrap_or(1);
-            *box_w = box_w.max(measure_w * (n_measures as f32 + 1.6666666666));
-            t.set_leading(leading)?;
-            for line in &form {
-                if let Some((first, rest)) = line.split_first() {
-                    t.pos(0., -leading)?;
-                    write_chord(t, first)?;
-                    for chord in rest {
-                        t.pos(measure_w - sl_w, 0.)?;
-                        t.show("| ")?;
-                        t.pos(sl_w, 0.)?;
-                        write_chord(t, chord)?;
-                    }
-                    for key in &keys {
-                        t.pos(*box_w - measure_w * rest.len() as f32, 0.)?;
-                        let key = Key::new(key)?;
-                        for chord in first.split_ascii_whitespace() {
-                            let chord = &key.from_nashville(&chord);
-                            chords.use_chord(chord);
-                            write_chord(t, chord)?;
-                        }
-                        for chord in rest {
-                            t.pos(measure_w - sl_w, 0.)?;
-                            t.show("| ")?;
-                            t.pos(sl_w, 0.)?;
-                            for chord in chord.split_ascii_whitespace() {
-                                let chord = &key.from_nashville(&chord);
-                                chords.use_chord(chord);
-                                write_chord(t, chord)?;
-                            }
-                        }
-                    }
-                    t.pos(
-                        measure_w
-                            - (*box_w * keys.len() as f32
-                                + measure_w * line.len() as f32),
-                        0.,
-                    )?;
-                }
-                y -= leading;
+/// Format all input files (or check that they are formatted), and
+/// exit with an error status if any file fails.
+fn format_files(args: &FmtArgs) {
+    let inputs = if args.input.is_empty() {
+        vec!["-".into()]
+    } else {
+        args.input.clone()
+    };
+    let mut n_failed = 0;
+    for name in &inputs {
+        match format_file(name, args.check) {
+            Ok(true) => (),
+            Ok(false) => n_failed += 1,
+            Err(e) => {
+                eprintln!("Failed to handle {name}: {e}");
+                n_failed += 1;
             }
-            t.grestore()?;
-            Ok(y - leading / 2.)
-        }),
-        ChordFileExpression::EndOfTab => {
-            println!("Warning: Stray end of tab in song!");
-            Ok(y)
-        }
-        ChordFileExpression::StartColumns { n_columns } => {
-            println!(
-                "Warning: StartColumns({n_columns}) should be handled earlier"
-            );
-            Ok(y)
-        }
-        ChordFileExpression::ColumnBreak | ChordFileExpression::PageBreak => {
-            Ok(0.0)
         }
-        ChordFileExpression::NewSong => Ok(std::f32::NEG_INFINITY),
-        ChordFileExpression::Line { s } => c.text(|t| {
-            let text_size = base_size;
-            let y = y - 1.1
-                * (if s.len() == 1 {
-                    text_size
-                } else {
-                    text_size + chord_size
-                });
-            t.set_font(&times, text_size)?;
-            t.pos(left, y)?;
-            let mut last_chord_width = 0.0;
-            for (i, part) in s.iter().enumerate() {
-                if i % 2 == 1 {
-                    chords.use_chord(part);
-                    t.gsave()?;
-                    t.set_rise(text_size * 0.9)?;
-                    t.set_fill_color(Color::gray(96))?;
-                    t.set_font(&chordfont, chord_size)?;
-                    let chord_width = chordfont.get_width_raw(part) as i32;
-                    t.show_adjusted(&[(part, chord_width)])?;
-                    last_chord_width =
-                        (chord_width + 400) as f32 * chord_size / 1000.0;
-                    t.grestore()?;
-                } else {
-                    let part = {
-                        if part.is_empty() {
-                            " "
-                        } else {
-                            part
-                        }
-                    };
-                    let text_width = times.get_width(text_size, part);
-                    if last_chord_width > text_width && i + 1 < s.len() {
-                        let extra = last_chord_width - text_width;
-                        let n_space =
-                            part.chars().filter(|&c| c == ' ').count();
-                        if n_space > 0 {
-                            t.set_word_spacing(extra / n_space as f32)?;
-                        } else {
-                            t.set_char_spacing(extra / part.len() as f32)?;
-                        }
-                    }
-                    t.show(part)?;
-                    if last_chord_width > text_width {
-                        t.set_char_spacing(0.0)?;
-                        t.set_word_spacing(0.0)?;
-                    }
-                }
-            }
-            Ok(y)
-        }),
+    }
+    if n_failed > 0 {
+        eprintln!("{n_failed} file(s) failed.");
+        exit(1);
     }
 }
 
-fn write_chord(t: &mut TextObject, chord: &str) -> io::Result<()> {
-    if chord.len() > 1 {
-        if let Some(x) = chord.strip_suffix('7') {
-            t.show(x)?;
-            t.set_rise(-1.8)?;
-            // TODO: Also slightly smaller?
-            t.show("7")?;
-            t.set_rise(0.)?;
-            t.show(" ")?;
-            return Ok(());
+/// Format a file, or check that it is formatted.
+///
+/// Returns false if the file has errors, or if `check` is true and
+/// the file is not formatted.
+fn format_file(name: &str, check: bool) -> io::Result<bool> {
+    let mut source = String::new();
+    open_input(name)?.read_to_string(&mut source)?;
+    let diagnostics = Diagnostics::new(source_name(name));
+    let formatted = format_source(&source, Path::new(name), &diagnostics);
+    if diagnostics.n_errors() > 0 {
+        eprintln!("Not formatting {}, it has errors.", source_name(name));
+        return Ok(false);
+    }
+    if check {
+        if formatted != source {
+            eprintln!("{} is not formatted.", source_name(name));
+            return Ok(false);
         }
+    } else if name == "-" {
+        print!("{formatted}");
+    } else if formatted != source {
+        std::fs::write(name, formatted)?;
     }
-    t.show(chord)?;
-    t.show(" ")
+    Ok(true)
 }
@@ -0,0 +1,92 @@
+/// Metadata of a song, such as artist, key, tempo and copyright.
+///
+/// Each name may have multiple values (e.g. a song may have more
+/// than one composer).  Values are kept in the order they are given.
+#[derive(Clone, Debug, Default)]
+pub struct Metadata {
+    values: Vec<(String, String)>,
+}
+
+impl Metadata {
+    pub fn add(&mut self, name: &str, value: &str) {
+        self.values
+            .push((name.to_lowercase(), value.trim().to_string()));
+    }
+
+    /// Get all values for `name`.
+    pub fn get_all<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> impl Iterator<Item = &'a str> + 'a {
+        self.values
+            .iter()
+            .filter(move |(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Get all values for `name`, joined by commas, or None if there
+    /// is no value.
+    pub fn get(&self, name: &str) -> Option<String> {
+        let values = self.get_all(name).collect::<Vec<_>>();
+        if values.is_empty() {
+            None
+        } else {
+            Some(values.join(", "))
+        }
+    }
+
+    /// Replace each value for `name` with `f(value)`.
+    pub fn transform(&mut self, name: &str, f: impl Fn(&str) -> String) {
+        for (n, value) in &mut self.values {
+            if n == name {
+                *value = f(value);
+            }
+        }
+    }
+
+    /// Format the metadata according to a template.
+    ///
+    /// The template consists of parts separated by `|`, where each
+    /// part may refer to metadata values as `%{name}`.
+    /// Parts that refers to a value missing in this metadata are
+    /// omitted, and the remaining parts are joined by a middle dot.
+    pub fn format(&self, template: &str) -> String {
+        template
+            .split('|')
+            .filter_map(|part| self.format_part(part))
+            .filter(|part| !part.trim().is_empty())
+            .collect::<Vec<_>>()
+            .join(" · ")
+    }
+
+    fn format_part(&self, part: &str) -> Option<String> {
+        let mut result = String::new();
+        let mut rest = part;
+        while let Some((before, after)) = rest.split_once("%{") {
+            let (name, after) = after.split_once('}')?;
+            result.push_str(before);
+            result.push_str(&self.get(name.trim())?);
+            rest = after;
+        }
+        result.push_str(rest);
+        Some(result.trim().to_string())
+    }
+}
+
+#[test]
+fn test_format() {
+    let mut meta = Metadata::default();
+    meta.add("key", "G");
+    meta.add("Tempo", "92");
+    meta.add("composer", "Lennon");
+    meta.add("composer", "McCartney");
+    assert_eq!(
+        meta.format("Key: %{key}|Capo %{capo}|%{tempo} bpm"),
+        "Key: G · 92 bpm",
+    );
+    assert_eq!(
+        meta.format("Music: %{composer}"),
+        "Music: Lennon, McCartney"
+    );
+    assert_eq!(meta.format("%{capo}"), "");
+}
@@ -1,36 +1,177 @@
-use crate::PageArgs;
+use std::str::FromStr;
+
+/// A paper size, in points (72 points = 1 inch), in portrait
+/// orientation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Paper {
+    width: f32,
+    height: f32,
+}
+
+impl Paper {
+    pub const A4: Paper = Paper::new(596., 842.);
+    pub const A5: Paper = Paper::new(420., 595.);
+    pub const B5: Paper = Paper::new(499., 709.);
+    pub const LETTER: Paper = Paper::new(612., 792.);
+    pub const LEGAL: Paper = Paper::new(612., 1008.);
+
+    /// A paper of the given size in points.
+    pub const fn new(width: f32, height: f32) -> Paper {
+        if width > height {
+            Paper {
+                width: height,
+                height: width,
+            }
+        } else {
+            Paper { width, height }
+        }
+    }
+
+    /// The scale of margins (and default font size) for this paper,
+    /// relative to A4.
+    ///
+    /// Papers larger than A4 gets the same margins as A4.
+    pub fn scale(&self) -> f32 {
+        (self.width / Paper::A4.width).min(1.)
+    }
+}
+
+impl Default for Paper {
+    fn default() -> Paper {
+        Paper::A4
+    }
+}
+
+impl FromStr for Paper {
+    type Err = String;
+
+    /// Parse a named paper size (A4, A5, B5, Letter or Legal), or a
+    /// custom size as `WIDTHxHEIGHT` with a unit of mm, cm, in or pt,
+    /// e.g. `148x210mm` or `5.5inx8.5in`.
+    fn from_str(s: &str) -> Result<Paper, String> {
+        match &*s.trim().to_lowercase() {
+            "a4" => Ok(Paper::A4),
+            "a5" => Ok(Paper::A5),
+            "b5" => Ok(Paper::B5),
+            "letter" => Ok(Paper::LETTER),
+            "legal" => Ok(Paper::LEGAL),
+            size => {
+                let err = || format!("Unknown paper size {s:?}");
+                let (width, height) = size.split_once('x').ok_or_else(err)?;
+                let (height, unit) = split_length(height).ok_or_else(err)?;
+                let (width, w_unit) = split_length(width).ok_or_else(err)?;
+                let w_unit = if w_unit.is_empty() { unit } else { w_unit };
+                let width = width * to_points(w_unit).ok_or_else(err)?;
+                let height = height * to_points(unit).ok_or_else(err)?;
+                if width < 72. || height < 72. {
+                    return Err(format!("Paper size {s:?} is too small"));
+                }
+                Ok(Paper::new(width, height))
+            }
+        }
+    }
+}
+
+/// Parse a length, such as a margin, in points.
+///
+/// The length is a number followed by an optional unit (mm, cm, in or
+/// pt, the default), e.g. `15mm` or `0.5in`.
+pub fn parse_length(s: &str) -> Result<f32, String> {
+    split_length(s)
+        .and_then(|(len, unit)| Some(len * to_points(unit)?))
+        .ok_or_else(|| format!("Bad length {s:?}"))
+}
+
+/// Parse a number followed by an optional unit.
+fn split_length(s: &str) -> Option<(f32, &str)> {
+    let s = s.trim();
+    let end = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    Some((s[..end].parse().ok()?, s[end..].trim()))
+}
+
+/// The number of points in a unit.
+fn to_points(unit: &str) -> Option<f32> {
+    match unit {
+        "pt" | "" => Some(1.),
+        "in" => Some(72.),
+        "mm" => Some(72. / 25.4),
+        "cm" => Some(72. / 2.54),
+        _ => None,
+    }
+}
+
+/// The page margins, in points.
+///
+/// The inner margin is on the spine side of the page (the left side
+/// of recto pages), and the gutter is extra space for the binding,
+/// added to the inner margin.
+/// The bottom margin is the baseline of the page footer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Margins {
+    pub top: f32,
+    pub bottom: f32,
+    pub inner: f32,
+    pub outer: f32,
+    pub gutter: f32,
+}
+
+impl Margins {
+    /// The default margins for a paper, scaled as `Paper::scale`.
+    pub fn for_paper(paper: Paper) -> Margins {
+        let scale = paper.scale();
+        Margins {
+            top: 20. * scale,
+            bottom: 20. * scale,
+            inner: 20. * scale,
+            outer: 20. * scale,
+            gutter: 50. * scale,
+        }
+    }
+}
 
 #[derive(Copy, Clone)]
 pub struct PageDim {
     width: f32,
     height: f32,
+    /// The scale of fixed distances, see `Paper::scale`.
+    scale: f32,
+    margins: Margins,
     pageno: u32,
     is_duplex: bool,
     show_pageno: bool,
 }
 
-impl From<PageArgs> for PageDim {
-    fn from(args: PageArgs) -> Self {
-        let a4 = (842.0, 596.0);
-        let (width, height) = if args.landscape {
-            (a4.0, a4.1)
+impl PageDim {
+    /// The first page of a document.
+    pub fn new(
+        paper: Paper,
+        landscape: bool,
+        is_duplex: bool,
+        show_pageno: bool,
+    ) -> Self {
+        let (width, height) = if landscape {
+            (paper.height, paper.width)
         } else {
-            (a4.1, a4.0)
+            (paper.width, paper.height)
         };
         PageDim {
             width,
             height,
+            scale: paper.scale(),
+            margins: Margins::for_paper(paper),
             pageno: 1,
-            is_duplex: !args.no_duplex,
-            show_pageno: !args.no_pageno,
+            is_duplex,
+            show_pageno,
         }
     }
-}
 
-const INNER: f32 = 70.;
-const OUTER: f32 = 20.;
+    /// Use other margins than the default for the paper.
+    pub fn with_margins(self, margins: Margins) -> Self {
+        PageDim { margins, ..self }
+    }
 
-impl PageDim {
     pub fn next(&self) -> PageDim {
         PageDim {
             pageno: self.pageno + 1,
@@ -41,11 +182,17 @@ impl PageDim {
     /// A page is verso (left or backside) if duplex is enabled and
     /// the page number is even (otherwise it is recto).
     pub fn is_verso(&self) -> bool {
-        self.is_duplex && (self.pageno % 2 == 0)
+        self.is_duplex && self.pageno.is_multiple_of(2)
+    }
+
+    /// The scale of margins and other fixed distances, relative to A4.
+    pub fn scale(&self) -> f32 {
+        self.scale
     }
 
     pub fn inner_width(&self) -> f32 {
-        self.width - (INNER + OUTER)
+        let m = &self.margins;
+        self.width - (m.inner + m.gutter + m.outer)
     }
     pub fn width(&self) -> f32 {
         self.width
@@ -62,19 +209,58 @@ impl PageDim {
     }
     pub fn left(&self) -> f32 {
         if self.is_verso() {
-            OUTER
+            self.margins.outer
         } else {
-            INNER
+            self.margins.inner + self.margins.gutter
         }
     }
     pub fn right(&self) -> f32 {
         if self.is_verso() {
-            self.width - INNER
+            self.width - (self.margins.inner + self.margins.gutter)
         } else {
-            self.width - OUTER
+            self.width - self.margins.outer
         }
     }
     pub fn top(&self) -> f32 {
-        self.height - 20.0
+        self.height - self.margins.top
     }
+    /// The baseline of the page footer (page number and copyright).
+    pub fn bottom(&self) -> f32 {
+        self.margins.bottom
+    }
+}
+
+#[test]
+fn test_paper() {
+    let paper = |s: &str| s.parse::<Paper>();
+    assert_eq!(paper("A4"), Ok(Paper::A4));
+    assert_eq!(paper("letter"), Ok(Paper::new(612., 792.)));
+    assert_eq!(paper("8.5x11in"), Ok(Paper::LETTER));
+    assert_eq!(paper("792 x 612"), Ok(Paper::LETTER));
+    assert_eq!(paper("2inx72pt"), Ok(Paper::new(144., 72.)));
+    let a5 = paper("148x210mm").unwrap();
+    assert!((a5.width - 419.5).abs() < 0.1 && (a5.height - 595.3).abs() < 0.1);
+    assert!(paper("A9").is_err());
+    assert!(paper("10x10mm").is_err());
+    assert!(paper("10x10 furlongs").is_err());
+    assert_eq!(Paper::LETTER.scale(), 1.);
+    assert!((Paper::A5.scale() - 0.705).abs() < 0.001);
+}
+
+#[test]
+fn test_margins() {
+    assert_eq!(parse_length("15"), Ok(15.));
+    assert_eq!(parse_length("0.5in"), Ok(36.));
+    assert_eq!(parse_length("2.54 cm"), Ok(72.));
+    assert!(parse_length("wide").is_err());
+    let margins = Margins {
+        inner: 30.,
+        gutter: 0.,
+        ..Margins::for_paper(Paper::A4)
+    };
+    let page = PageDim::new(Paper::A4, false, true, true).with_margins(margins);
+    assert_eq!((page.left(), page.right()), (30., 576.));
+    let page = page.next();
+    assert_eq!((page.left(), page.right()), (20., 566.));
+    assert_eq!(page.inner_width(), 546.);
 }
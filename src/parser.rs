@@ -0,0 +1,864 @@
+//! Parser for the chopro format.
+//!
+//! The format is described in `chopro.md`.  A [`ChoproParser`] reads a
+//! chopro source and yields a [`Token`] for each expression.
+use crate::diagnostics::{Code, Diagnostics, Pos};
+use regex::{Captures, Regex};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// An expression in a chopro file.
+///
+/// Choruses and other sections contain their lines, so the matching
+/// end markers are only seen when they are stray.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChordFileExpression {
+    Title { s: String },
+    SubTitle { s: String },
+    Comment { s: String },
+    Meta { name: String, value: String },
+    /// A chord definition, see `ChordHolder::define`.
+    ChordDef {
+        name: String,
+        def: Vec<i8>,
+        fingers: Vec<u8>,
+    },
+    Chorus {
+        label: Option<String>,
+        lines: Vec<Token>,
+    },
+    EndOfChorus,
+    ChorusRecall {
+        label: Option<String>,
+    },
+    Section {
+        kind: SectionKind,
+        label: Option<String>,
+        lines: Vec<Token>,
+    },
+    EndOfSection {
+        kind: SectionKind,
+    },
+    Tab { lines: Vec<String> },
+    Form {
+        name: String,
+        keys: Vec<String>,
+        form: Vec<Vec<String>>,
+    },
+    EndOfTab,
+    StartColumns { n_columns: u8 },
+    /// Transpose the following chords of the song.
+    Transpose { semitones: i8 },
+    ColumnBreak,
+    PageBreak,
+    NewSong,
+    Line { segments: Vec<Segment> },
+    /// A directive that is not understood, kept verbatim.
+    Unknown { s: String },
+    /// An `{include}` directive (only kept in verbatim mode).
+    Include { name: String },
+    /// A source line kept as written (only in verbatim mode): a `#`
+    /// comment, or a line with a directive that can not be rewritten
+    /// without losing something, such as text after the directive.
+    Verbatim { s: String },
+}
+
+impl ChordFileExpression {
+    /// True for end markers of choruses, tabs and other sections.
+    pub fn is_end(&self) -> bool {
+        matches!(
+            self,
+            ChordFileExpression::EndOfChorus
+                | ChordFileExpression::EndOfSection { .. }
+                | ChordFileExpression::EndOfTab
+        )
+    }
+}
+
+/// A chopro expression and the position where it starts.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Token {
+    pub pos: Pos,
+    pub expr: ChordFileExpression,
+    /// The selector suffix of the directive, if any.
+    pub selector: Option<String>,
+}
+
+/// A part of a lyrics line: a chord and the lyrics sung from it.
+///
+/// The first segment of a line has no chord (and empty lyrics if the
+/// line starts with a chord), all following segments have a chord.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Segment {
+    pub chord: Option<String>,
+    pub lyrics: String,
+}
+
+impl Segment {
+    /// Split a lyrics line with chords in brackets into segments.
+    pub fn parse_line(line: &str) -> Vec<Segment> {
+        let re = Regex::new(r"([^\[]*)(?:\[([^\]]*)\])?").unwrap();
+        let mut segments = vec![Segment {
+            chord: None,
+            lyrics: String::new(),
+        }];
+        for caps in re.captures_iter(line) {
+            if let Some(last) = segments.last_mut() {
+                last.lyrics.push_str(&caps[1]);
+            }
+            if let Some(chord) = caps.get(2) {
+                segments.push(Segment {
+                    chord: Some(chord.as_str().to_string()),
+                    lyrics: String::new(),
+                });
+            }
+        }
+        segments
+    }
+}
+
+/// True if the `line` is nothing but the directive matched by `caps`,
+/// and that directive is fully kept by the parser.
+///
+/// A chord definition with `keys` is not fully kept, since the keys
+/// are ignored.
+fn is_plain_directive(line: &str, caps: &Captures) -> bool {
+    let whole = caps.get(0).unwrap();
+    let is_define = caps["cmd"].eq_ignore_ascii_case("define");
+    let has_keys = caps.name("arg").is_some_and(|arg| {
+        arg.as_str()
+            .split_whitespace()
+            .any(|word| word.eq_ignore_ascii_case("keys"))
+    });
+    line[..whole.start()].trim().is_empty()
+        && line[whole.end()..].trim().is_empty()
+        && !(is_define && has_keys)
+}
+
+/// The width of a tab stop, in characters.
+const TAB_WIDTH: usize = 4;
+
+/// Replace tabs in the lyrics of a line with spaces up to the next tab
+/// stop.
+///
+/// The column is counted in the lyrics only, since the chords are not
+/// part of the lyrics line.
+fn expand_tabs(segments: &mut [Segment]) {
+    let mut col = 0;
+    for segment in segments {
+        if !segment.lyrics.contains('\t') {
+            col += segment.lyrics.chars().count();
+            continue;
+        }
+        let mut lyrics = String::with_capacity(segment.lyrics.len());
+        for c in segment.lyrics.chars() {
+            if c == '\t' {
+                let n = TAB_WIDTH - col % TAB_WIDTH;
+                lyrics.extend(std::iter::repeat_n(' ', n));
+                col += n;
+            } else {
+                lyrics.push(c);
+                col += 1;
+            }
+        }
+        segment.lyrics = lyrics;
+    }
+}
+
+#[test]
+fn test_expand_tabs() {
+    let mut segments = Segment::parse_line("a\tb[G]cd\te\t[C]\tf");
+    expand_tabs(&mut segments);
+    let lyrics = segments.iter().map(|s| &*s.lyrics).collect::<Vec<_>>();
+    assert_eq!(lyrics, vec!["a   b", "cd e   ", "    f"]);
+}
+
+#[test]
+fn test_parse_line() {
+    let segment = |chord: Option<&str>, lyrics: &str| Segment {
+        chord: chord.map(Into::into),
+        lyrics: lyrics.into(),
+    };
+    assert_eq!(Segment::parse_line(""), vec![segment(None, "")]);
+    assert_eq!(
+        Segment::parse_line("[G]Hello [C]world"),
+        vec![
+            segment(None, ""),
+            segment(Some("G"), "Hello "),
+            segment(Some("C"), "world"),
+        ],
+    );
+    assert_eq!(
+        Segment::parse_line("Hey [Am7]"),
+        vec![segment(None, "Hey "), segment(Some("Am7"), "")],
+    );
+}
+
+/// The kind of a generic `{start_of_...}` / `{end_of_...}` section.
+///
+/// Choruses, tabs and forms have their own expressions, since they
+/// are rendered differently.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SectionKind {
+    Verse,
+    Bridge,
+    Other(String),
+}
+
+impl SectionKind {
+    pub fn from_name(name: &str) -> SectionKind {
+        match name {
+            "verse" => SectionKind::Verse,
+            "bridge" => SectionKind::Bridge,
+            other => SectionKind::Other(other.to_string()),
+        }
+    }
+    pub fn name(&self) -> &str {
+        match self {
+            SectionKind::Verse => "verse",
+            SectionKind::Bridge => "bridge",
+            SectionKind::Other(name) => name,
+        }
+    }
+}
+
+/// Get the label of a section from the argument of its start
+/// directive, either `{soc: Label}` or `{soc: label="Label"}`.
+fn section_label(arg: &str) -> Option<String> {
+    let arg = arg.trim();
+    let arg = match arg.strip_prefix("label=") {
+        Some(label) => label.trim_matches('"'),
+        None => arg,
+    };
+    Some(arg.to_string()).filter(|s| !s.is_empty())
+}
+
+#[test]
+fn test_section_label() {
+    assert_eq!(section_label(""), None);
+    assert_eq!(section_label("Chorus 2"), Some("Chorus 2".into()));
+    assert_eq!(section_label("label=\"Verse 1\""), Some("Verse 1".into()));
+}
+
+/// Check if a selector matches any of the active `selectors`.
+///
+/// A selector starting with `!` is negated.
+pub fn is_selected(selector: &str, selectors: &[String]) -> bool {
+    let (negated, selector) = match selector.strip_prefix('!') {
+        Some(selector) => (true, selector),
+        None => (false, selector),
+    };
+    let found = selectors
+        .iter()
+        .any(|active| active.eq_ignore_ascii_case(selector));
+    found != negated
+}
+
+/// Parse the argument of a `{define}` directive.
+///
+/// The argument is a chord name followed by `base-fret N` (optional),
+/// `frets` with a fret for each string, optionally `fingers` with a
+/// finger for each string, and optionally `keys` (which is ignored).
+/// A string that is not played is given as `x`, `N` or `-`.
+///
+/// Returns the name, the base fret followed by the frets, and the
+/// fingers (empty if not given).
+pub fn parse_define(arg: &str) -> Option<(String, Vec<i8>, Vec<u8>)> {
+    enum Part {
+        Frets,
+        Fingers,
+        Keys,
+    }
+    let mut words = arg.split_whitespace();
+    let name = words.next()?.to_string();
+    let mut base_fret = 1;
+    let mut frets = vec![];
+    let mut fingers = vec![];
+    let mut part = None;
+    while let Some(word) = words.next() {
+        match &*word.to_lowercase() {
+            "base-fret" => {
+                base_fret = words.next()?.parse().ok()?;
+            }
+            "frets" => part = Some(Part::Frets),
+            "fingers" => part = Some(Part::Fingers),
+            "keys" => part = Some(Part::Keys),
+            "x" | "n" | "-" => match part {
+                Some(Part::Frets) => frets.push(-1),
+                Some(Part::Fingers) => fingers.push(0),
+                _ => return None,
+            },
+            value => match part {
+                Some(Part::Frets) => {
+                    let fret = value.parse().ok();
+                    frets.push(fret.filter(|f| (0..25).contains(f))?)
+                }
+                Some(Part::Fingers) => {
+                    fingers.push(value.parse().ok().filter(|f| *f < 5)?)
+                }
+                Some(Part::Keys) => (),
+                None => return None,
+            },
+        }
+    }
+    if frets.is_empty() || !(fingers.is_empty() || fingers.len() == frets.len())
+    {
+        return None;
+    }
+    let mut def = vec![base_fret];
+    def.extend(frets);
+    Some((name, def, fingers))
+}
+
+#[test]
+fn test_parse_define() {
+    assert_eq!(
+        parse_define("C/G base-fret 0 frets 3 3 2 0 1 0"),
+        Some(("C/G".into(), vec![0, 3, 3, 2, 0, 1, 0], vec![])),
+    );
+    assert_eq!(
+        parse_define("Am base-fret 1 frets x 0 2 2 1 0"),
+        Some(("Am".into(), vec![1, -1, 0, 2, 2, 1, 0], vec![])),
+    );
+    assert_eq!(
+        parse_define("F#m frets 2 4 4 2 2 2 fingers 1 3 4 1 1 1"),
+        Some((
+            "F#m".into(),
+            vec![1, 2, 4, 4, 2, 2, 2],
+            vec![1, 3, 4, 1, 1, 1]
+        )),
+    );
+    assert_eq!(
+        parse_define("A9 base-fret 10 frets N 12 11 12 12 x"),
+        Some(("A9".into(), vec![10, -1, 12, 11, 12, 12, -1], vec![])),
+    );
+    assert_eq!(parse_define("Am frets 0 2 2 fingers 1 2"), None);
+    assert_eq!(parse_define("Am fingers 0 2 2"), None);
+}
+
+/// A parser for chopro sources.
+///
+/// Iterate over the parser to get the tokens of the source.
+/// Problems in the source are reported to the `Diagnostics`.
+pub struct ChoproParser<'a, R: io::Read> {
+    source: Mutex<io::Lines<io::BufReader<R>>>,
+    /// The path of the main source, includes are relative to this.
+    path: PathBuf,
+    /// Files currently being included, innermost last.
+    includes: Vec<Include>,
+    diagnostics: &'a Diagnostics,
+    /// Directives with a selector suffix (e.g. `{define-mandolin}`)
+    /// are ignored unless the selector is one of these.
+    selectors: &'a [String],
+    /// Keep the source as written, see `ChoproParser::verbatim`.
+    verbatim: bool,
+    /// True while parsing a directive (or section) with a selector
+    /// that does not match, so problems in it are not reported.
+    ignoring: bool,
+    lineno: usize,
+    eof: bool,
+}
+
+/// A file included by an `{include}` directive.
+struct Include {
+    lines: io::Lines<io::BufReader<File>>,
+    path: PathBuf,
+    /// The index of this file in the diagnostics sources.
+    source: usize,
+    lineno: usize,
+}
+
+impl<'a, R: io::Read> ChoproParser<'a, R> {
+    /// Create a parser for `source`, read from `path`.
+    ///
+    /// The path is used to resolve `{include}` directives (and the
+    /// source itself does not need to be read from it).
+    pub fn new(
+        source: R,
+        path: &Path,
+        diagnostics: &'a Diagnostics,
+        selectors: &'a [String],
+    ) -> ChoproParser<'a, R> {
+        let reader = io::BufReader::new(source);
+        ChoproParser {
+            source: Mutex::new(reader.lines()),
+            path: path.into(),
+            includes: vec![],
+            diagnostics,
+            selectors,
+            verbatim: false,
+            ignoring: false,
+            lineno: 0,
+            eof: false,
+        }
+    }
+
+    /// Keep the source as written, rather than as it should be rendered.
+    ///
+    /// In verbatim mode, `#` comments and `{include}` directives are
+    /// kept as tokens, and directives are kept regardless of their
+    /// selectors.  Lines with a directive and other text, and chord
+    /// definitions with `keys`, are kept as written.  This is useful
+    /// for rewriting a source.
+    pub fn verbatim(self) -> Self {
+        ChoproParser {
+            verbatim: true,
+            ..self
+        }
+    }
+
+    // Internal: Return the next line that is not a comment
+    // (comments are only returned in verbatim mode).
+    fn nextline(&mut self) -> Option<String> {
+        let comment_re = Regex::new(r"^\s*#").unwrap();
+        loop {
+            let line = if let Some(include) = self.includes.last_mut() {
+                include.lineno += 1;
+                match include.lines.next() {
+                    Some(Ok(line)) => Some(Ok(line)),
+                    Some(Err(e)) => {
+                        self.diagnostics.error(
+                            self.pos(),
+                            Code::ReadError,
+                            format_args!("Failed to read source: {e}"),
+                        );
+                        self.includes.pop();
+                        continue;
+                    }
+                    None => {
+                        self.includes.pop();
+                        continue;
+                    }
+                }
+            } else {
+                self.lineno += 1;
+                self.source.lock().unwrap().next()
+            };
+            match line {
+                Some(Ok(line)) => {
+                    if self.verbatim || !comment_re.is_match(&line) {
+                        return Some(line);
+                    }
+                }
+                Some(Err(e)) => {
+                    self.diagnostics.error(
+                        self.pos(),
+                        Code::ReadError,
+                        format_args!("Failed to read source: {e}"),
+                    );
+                    self.eof = true;
+                    return None;
+                }
+                _ => {
+                    self.eof = true;
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// The position of the start of the current line.
+    fn pos(&self) -> Pos {
+        match self.includes.last() {
+            Some(include) => Pos::in_source(include.source, include.lineno, 1),
+            None => Pos::new(self.lineno, 1),
+        }
+    }
+
+    /// Start reading lines from an included file.
+    ///
+    /// The file name is relative to the including file.
+    fn include(&mut self, pos: Pos, name: &str) {
+        let current = self.includes.last().map_or(&self.path, |i| &i.path);
+        let path = current.parent().unwrap_or(Path::new("")).join(name);
+        let canonical = path.canonicalize().ok();
+        let is_cycle = canonical.is_some()
+            && (self.path.canonicalize().ok() == canonical
+                || self
+                    .includes
+                    .iter()
+                    .any(|i| i.path.canonicalize().ok() == canonical));
+        if is_cycle {
+            self.diagnostics.error(
+                pos,
+                Code::IncludeCycle,
+                format_args!("Cyclic include of {}", path.display()),
+            );
+            return;
+        }
+        match File::open(&path) {
+            Ok(f) => {
+                let source =
+                    self.diagnostics.add_source(&path.display().to_string());
+                self.includes.push(Include {
+                    lines: io::BufReader::new(f).lines(),
+                    path,
+                    source,
+                    lineno: 0,
+                });
+            }
+            Err(e) => self.diagnostics.error(
+                pos,
+                Code::ReadError,
+                format_args!("Failed to include {}: {e}", path.display()),
+            ),
+        }
+    }
+
+    pub fn is_eof(&self) -> bool {
+        self.eof
+    }
+
+    // Internal: Collect the lines of a section up to its end marker.
+    fn section(
+        &mut self,
+        pos: Pos,
+        kind: SectionKind,
+        arg: &str,
+    ) -> ChordFileExpression {
+        let mut lines = vec![];
+        loop {
+            match self.next() {
+                Some(Token {
+                    expr: ChordFileExpression::EndOfSection { kind: ref end },
+                    ..
+                }) if *end == kind => break,
+                Some(line) => lines.push(line),
+                None => {
+                    self.unterminated(pos, kind.name());
+                    break;
+                }
+            }
+        }
+        ChordFileExpression::Section {
+            kind,
+            label: section_label(arg),
+            lines,
+        }
+    }
+
+    /// Check if a directive selector matches the active selectors.
+    fn is_selected(&self, selector: &str) -> bool {
+        is_selected(selector, self.selectors)
+    }
+
+    fn unterminated(&self, pos: Pos, what: &str) {
+        self.diagnostics.error(
+            pos,
+            Code::Unterminated,
+            format_args!("Unterminated {what}, no end found"),
+        );
+    }
+}
+
+impl<R: io::Read> Iterator for ChoproParser<'_, R> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        loop {
+            let line = self.nextline()?;
+            if let Some(token) = self.parse_line(line) {
+                return Some(token);
+            }
+        }
+    }
+}
+
+impl<R: io::Read> ChoproParser<'_, R> {
+    /// Parse a line (and any following lines that belong to the same
+    /// expression, such as the contents of a chorus).
+    ///
+    /// Returns None for a directive with a selector that does not
+    /// match, and for an include directive (unless in verbatim mode).
+    fn parse_line(&mut self, line: String) -> Option<Token> {
+        let pos = self.pos();
+        if self.verbatim && line.trim_start().starts_with('#') {
+            let expr = ChordFileExpression::Verbatim { s: line };
+            return Some(Token {
+                pos,
+                expr,
+                selector: None,
+            });
+        }
+        let re = Regex::new(
+            r"\{(?P<cmd>\w+)(?:-(?P<sel>!?\w+))?(?::?\s*(?P<arg>.*))?\}",
+        )
+        .unwrap();
+        if let Some(caps) = re.captures(&line) {
+            let whole = caps.get(0).unwrap();
+            if self.verbatim && !is_plain_directive(&line, &caps) {
+                let expr = ChordFileExpression::Verbatim { s: line };
+                return Some(Token {
+                    pos,
+                    expr,
+                    selector: None,
+                });
+            }
+            let pos = pos.right(line[..whole.start()].chars().count());
+            let arg = caps.name("arg").map_or("", |m| m.as_str()).to_string();
+            let selector = caps.name("sel").map(|s| s.as_str().to_string());
+            let ignored = selector.as_ref().is_some_and(|selector| {
+                !(self.verbatim || self.is_selected(selector))
+            });
+            let cmd = caps.name("cmd").unwrap().as_str().to_lowercase();
+            let was_ignoring = self.ignoring;
+            self.ignoring |= ignored;
+            let expr = self.directive(pos, &cmd, arg, whole.as_str());
+            self.ignoring = was_ignoring;
+            let expr = expr?;
+            if ignored && !expr.is_end() {
+                return None;
+            }
+            Some(Token {
+                pos,
+                expr,
+                selector,
+            })
+        } else {
+            let mut segments = Segment::parse_line(&line);
+            expand_tabs(&mut segments);
+            let expr = ChordFileExpression::Line { segments };
+            Some(Token {
+                pos,
+                expr,
+                selector: None,
+            })
+        }
+    }
+
+    /// Parse a directive `cmd` with its `arg` (and any following lines
+    /// that belong to it, such as the contents of a chorus).
+    ///
+    /// Returns None for an include directive (unless in verbatim mode).
+    fn directive(
+        &mut self,
+        pos: Pos,
+        cmd: &str,
+        arg: String,
+        whole: &str,
+    ) -> Option<ChordFileExpression> {
+        let expr = match cmd {
+            "title" | "t" => ChordFileExpression::Title { s: arg },
+            "subtitle" | "st" => ChordFileExpression::SubTitle { s: arg },
+            "comment" | "c" | "ci" | "cb" => {
+                ChordFileExpression::Comment { s: arg }
+            }
+            name @ ("artist" | "composer" | "lyricist" | "arranger"
+            | "album" | "year" | "key" | "capo" | "tempo" | "time"
+            | "duration" | "copyright") => ChordFileExpression::Meta {
+                name: name.to_string(),
+                value: arg,
+            },
+            "meta" => {
+                let (name, value) = arg
+                    .trim()
+                    .split_once(char::is_whitespace)
+                    .unwrap_or((arg.trim(), ""));
+                ChordFileExpression::Meta {
+                    name: name.to_lowercase(),
+                    value: value.to_string(),
+                }
+            }
+            "define" => match parse_define(&arg) {
+                Some((name, def, fingers)) => {
+                    ChordFileExpression::ChordDef { name, def, fingers }
+                }
+                None => {
+                    self.warning(
+                        pos,
+                        Code::BadChordDef,
+                        format_args!("Bad chord definition {whole}"),
+                    );
+                    ChordFileExpression::Unknown {
+                        s: whole.to_string(),
+                    }
+                }
+            },
+            "soc" | "start_of_chorus" => {
+                let mut lines = vec![];
+                loop {
+                    match self.next() {
+                        Some(Token {
+                            expr: ChordFileExpression::EndOfChorus,
+                            ..
+                        }) => break,
+                        Some(line) => lines.push(line),
+                        None => {
+                            self.unterminated(pos, "chorus");
+                            break;
+                        }
+                    }
+                }
+                ChordFileExpression::Chorus {
+                    label: section_label(&arg),
+                    lines,
+                }
+            }
+            "eoc" | "end_of_chorus" => ChordFileExpression::EndOfChorus,
+            "chorus" => ChordFileExpression::ChorusRecall {
+                label: section_label(&arg),
+            },
+            "sot" | "start_of_tab" => {
+                let mut lines = vec![];
+                let end = Regex::new(r"\{(eot|end_of_tab):?\s*").unwrap();
+                loop {
+                    match self.nextline() {
+                        Some(line) if end.is_match(&line) => break,
+                        Some(line) => lines.push(line),
+                        None => {
+                            self.unterminated(pos, "tab");
+                            break;
+                        }
+                    }
+                }
+                ChordFileExpression::Tab { lines }
+            }
+            "eot" | "end_of_tab" => ChordFileExpression::EndOfTab,
+            "sof" | "start_of_form" => {
+                let mut arg = arg.split("//");
+                let mut form = vec![];
+                let end = Regex::new(r"\{e(of|nd_of_form):?\s*").unwrap();
+                loop {
+                    let line = match self.nextline() {
+                        Some(line) if end.is_match(&line) => break,
+                        Some(line) => line,
+                        None => {
+                            self.unterminated(pos, "form");
+                            break;
+                        }
+                    };
+                    form.push(
+                        line.split('/')
+                            .map(str::trim)
+                            .map(Into::into)
+                            .collect::<Vec<String>>(),
+                    );
+                }
+                ChordFileExpression::Form {
+                    name: arg.next().unwrap_or_default().into(),
+                    keys: arg.map(|s| s.trim().into()).collect(),
+                    form,
+                }
+            }
+            "eof" | "end_of_form" => ChordFileExpression::EndOfTab,
+
+            "columns" | "col" => {
+                match arg.trim().parse::<u8>().ok().filter(|n| *n > 0) {
+                    Some(n_columns) => {
+                        ChordFileExpression::StartColumns { n_columns }
+                    }
+                    None => {
+                        self.error(
+                            pos,
+                            Code::BadColumns,
+                            format_args!("Bad number of columns {arg:?}"),
+                        );
+                        ChordFileExpression::Unknown {
+                            s: whole.to_string(),
+                        }
+                    }
+                }
+            }
+            "transpose" => match arg.trim().parse::<i8>() {
+                Ok(semitones) => ChordFileExpression::Transpose { semitones },
+                Err(_) => {
+                    self.error(
+                        pos,
+                        Code::BadTranspose,
+                        format_args!("Bad transposition {arg:?}"),
+                    );
+                    ChordFileExpression::Unknown {
+                        s: whole.to_string(),
+                    }
+                }
+            },
+            "include" if self.verbatim => ChordFileExpression::Include {
+                name: arg.trim().to_string(),
+            },
+            "include" => {
+                if !self.ignoring {
+                    self.include(pos, arg.trim());
+                }
+                return None;
+            }
+            "colb" | "column_break" => ChordFileExpression::ColumnBreak,
+            "page_break" | "new_page" | "np" => ChordFileExpression::PageBreak,
+            "new_song" => ChordFileExpression::NewSong,
+            "sov" | "start_of_verse" => {
+                self.section(pos, SectionKind::Verse, &arg)
+            }
+            "eov" | "end_of_verse" => ChordFileExpression::EndOfSection {
+                kind: SectionKind::Verse,
+            },
+            "sob" | "start_of_bridge" => {
+                self.section(pos, SectionKind::Bridge, &arg)
+            }
+            "eob" | "end_of_bridge" => ChordFileExpression::EndOfSection {
+                kind: SectionKind::Bridge,
+            },
+            x if x.starts_with("start_of_") => {
+                let kind = SectionKind::from_name(&x[9..]);
+                self.section(pos, kind, &arg)
+            }
+            x if x.starts_with("end_of_") => {
+                ChordFileExpression::EndOfSection {
+                    kind: SectionKind::from_name(&x[7..]),
+                }
+            }
+            x => {
+                self.warning(
+                    pos,
+                    Code::UnknownDirective,
+                    format_args!("Unknown directive {x}"),
+                );
+                ChordFileExpression::Unknown {
+                    s: whole.to_string(),
+                }
+            }
+        };
+        Some(expr)
+    }
+
+    /// Report a warning, unless parsing ignored content.
+    fn warning(&self, pos: Pos, code: Code, message: impl fmt::Display) {
+        if !self.ignoring {
+            self.diagnostics.warning(pos, code, message);
+        }
+    }
+
+    /// Report an error, unless parsing ignored content.
+    fn error(&self, pos: Pos, code: Code, message: impl fmt::Display) {
+        if !self.ignoring {
+            self.diagnostics.error(pos, code, message);
+        }
+    }
+}
+
+#[test]
+fn test_ignored_selector() {
+    let n_problems = |source: &str| {
+        let diagnostics = Diagnostics::new("test");
+        let selectors = ["guitar".to_string()];
+        let path = Path::new("test");
+        let parser = ChoproParser::new(
+            source.as_bytes(),
+            path,
+            &diagnostics,
+            &selectors,
+        );
+        parser.for_each(drop);
+        diagnostics.n_warnings() + diagnostics.n_errors()
+    };
+    assert_eq!(
+        n_problems(
+            "{define-ukulele: Am frets 2 0 0 0 0 0 0}\n\
+             {columns-bass: many}\n\
+             {start_of_chorus-ukulele}\n{foo}\n{end_of_chorus}\n"
+        ),
+        0
+    );
+    assert_eq!(n_problems("{define-guitar: Am frets}\n"), 1);
+}
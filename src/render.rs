@@ -0,0 +1,2159 @@
+//! Render songs to pdf.
+use crate::chord::Chord;
+use crate::chords::{fit_shape, ChordHolder, Instrument, Tuning};
+use crate::diagnostics::{Code, Diagnostics, Pos};
+use crate::key::Key;
+use crate::metadata::Metadata;
+use crate::pagedim::PageDim;
+use crate::parser::{ChordFileExpression, SectionKind, Segment, Token};
+use crate::song::{ChordDef, Song};
+use clap::ValueEnum;
+use pdf_canvas::graphicsstate::Color;
+use pdf_canvas::{BuiltinFont, Canvas, FontSource, Pdf, TextObject};
+use std::io;
+use std::path::Path;
+
+fn chordbox(
+    c: &mut Canvas<'_>,
+    left: f32,
+    top: f32,
+    name: &str,
+    strings: &[i8],
+    chords: &ChordHolder,
+    base_size: f32,
+) -> io::Result<()> {
+    let n_strings = (strings.len() - 1) as u8;
+    let n_bands = chords.n_frets();
+    let (dx, dy) = box_spacing(n_bands, base_size);
+    let right = left + f32::from(n_strings - 1) * dx;
+    let bottom = top - (f32::from(n_bands) + 0.4) * dy;
+    let radius = 1.4;
+    c.center_text(
+        (left + right) / 2.0,
+        top + 2.0 + 4.0 * radius,
+        BuiltinFont::Helvetica_Oblique,
+        12.0,
+        name,
+    )?;
+    let barre = strings[0];
+    let up = if barre < 2 {
+        c.set_line_width(1.0)?;
+        c.line(left - 0.15, top + 0.5, right + 0.15, top + 0.5)?;
+        c.stroke()?;
+        for mark in &[5_u8, 7, 10] {
+            if n_bands >= *mark {
+                c.right_text(
+                    left - 0.4 * dx,
+                    top - (f32::from(*mark) - 0.1) * dy,
+                    BuiltinFont::Helvetica,
+                    dy,
+                    &format!("{mark}"),
+                )?;
+            }
+        }
+        0.0
+    } else {
+        c.right_text(
+            left - 0.4 * dx,
+            top - 0.9 * dy,
+            BuiltinFont::Helvetica,
+            dy,
+            &format!("{barre}"),
+        )?;
+        1.6
+    };
+    c.set_line_width(0.3)?;
+    for b in 0..=n_bands {
+        let y = top - f32::from(b) * dy;
+        c.line(left, y, right, y)?;
+    }
+    for s in 0..n_strings {
+        let x = left + f32::from(s) * dx;
+        c.line(x, top + up, x, bottom)?;
+    }
+    c.stroke()?;
+    let radius = base_size * 0.11;
+    let above = top + 2.0 + radius;
+    for (string, band) in strings[1..].iter().enumerate() {
+        let x = left + string as f32 * dx;
+        match *band {
+            -2 => (), // No-op for unknown chord
+            -1 => {
+                let (xl, xr) = (x - radius, x + radius);
+                let (yt, yb) = (above - radius, above + radius);
+                c.line(xl, yt, xr, yb)?;
+                c.line(xr, yt, xl, yb)?;
+                c.stroke()?;
+            }
+            0 => {
+                c.circle(x, above, radius)?;
+                c.stroke()?;
+            }
+            band => {
+                let y = top - (f32::from(band) - 0.5) * dy;
+                c.circle(x, y, radius * 1.2)?;
+                c.fill()?;
+            }
+        }
+    }
+    let fingers = chords.get_fingers(name).unwrap_or_default();
+    for (string, finger) in fingers.iter().enumerate() {
+        if *finger > 0 {
+            let x = left + string as f32 * dx;
+            c.center_text(
+                x,
+                bottom - dy,
+                BuiltinFont::Helvetica,
+                dy,
+                &format!("{finger}"),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Render a page with all known chords for the instrument.
+///
+/// If a tuning other than the standard tuning is given, only chords
+/// from chord db files are known.
+pub fn render_chordlist(
+    document: &mut Pdf,
+    page: PageDim,
+    options: &RenderOptions,
+) -> io::Result<()> {
+    let chords = options.chords();
+    let base_size = options.base_size;
+
+    document.render_page(page.width(), page.height(), |c| {
+        let s = "Chords";
+        c.add_outline(s);
+        c.left_text(
+            page.left(),
+            page.top() - 1.5 * base_size,
+            BuiltinFont::Times_Bold,
+            base_size * 4. / 3.,
+            s,
+        )?;
+        render_chordboxes(c, page, chords.get_all_chords(), &chords, base_size)
+    })
+}
+
+/// Render an appendix with the chords used in all songs, as collected
+/// by `render_source`, starting on `page`.
+///
+/// Returns the last page used, or None if there are no chord boxes to
+/// show.
+pub fn render_appendix(
+    document: &mut Pdf,
+    page: PageDim,
+    appendix: &ChordHolder,
+    options: &RenderOptions,
+) -> io::Result<Option<PageDim>> {
+    let base_size = options.base_size;
+    let used = appendix.get_used();
+    let title = "Chords";
+    let top = page.top() - 1.5 * base_size;
+    let width = page.inner_width();
+    let Some(grid) = BoxGrid::new(width, &used, appendix, base_size) else {
+        return Ok(None);
+    };
+    // Split the grid into pages, as many rows on each as fits below
+    // the title.
+    let height = top - base_size - page.bottom() / 2. - grid.head;
+    let rows_per_page = (height / grid.box_height).max(1.) as usize;
+    let per_page = rows_per_page * grid.n_aside as usize;
+    let mut page = page;
+    for (i, used) in used.chunks(per_page).enumerate() {
+        if i > 0 {
+            page = page.next();
+        }
+        document.render_page(page.width(), page.height(), |c| {
+            if i == 0 {
+                c.add_outline(title);
+                c.left_text(
+                    page.left(),
+                    top,
+                    BuiltinFont::Times_Bold,
+                    base_size * 4. / 3.,
+                    title,
+                )?;
+            }
+            write_pageno(c, &page)?;
+            let grid = BoxGrid::new(width, used, appendix, base_size);
+            if let Some(grid) = grid {
+                let top = if i == 0 { top - base_size } else { page.top() };
+                let placement = Placement::new(grid, page.right(), top);
+                let used = used.to_vec();
+                render_placed_chordboxes(
+                    c, &placement, used, appendix, base_size,
+                )?;
+            }
+            Ok(())
+        })?;
+    }
+    Ok(Some(page))
+}
+
+/// How to render a `{chorus}` directive, that recalls the last chorus.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum ChorusRecall {
+    /// Render the chorus again, in full.
+    Full,
+    /// Render a short reference to the chorus.
+    #[default]
+    Reference,
+}
+
+/// How to name chords in songs with a capo.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum CapoChords {
+    /// The shapes played, relative to the capo, as written.
+    #[default]
+    Shapes,
+    /// The chords that sounds, i.e. the shapes transposed by the capo.
+    Sounding,
+    /// Both the shape and, in parentheses, the sounding chord.
+    Both,
+}
+
+/// Options for how to render (or check) songs.
+#[derive(Default)]
+pub struct RenderOptions {
+    pub show_sourcename: bool,
+    pub instrument: Instrument,
+    /// A tuning to use instead of the standard tuning of `instrument`.
+    pub tuning: Option<Tuning>,
+    pub base_size: f32,
+    /// Template for the metadata line, see `Metadata::format`.
+    pub header: String,
+    pub selectors: Vec<String>,
+    pub chorus_recall: ChorusRecall,
+    /// How to name chords in songs with a capo.
+    pub capo_chords: CapoChords,
+    /// Semitones to transpose all songs.
+    pub transpose: i8,
+    /// Chord definitions from chord db files.
+    pub chord_db: Vec<ChordDef>,
+    /// How to fit songs on pages, by decreasing the base size.
+    pub fit: Fit,
+    /// The smallest base size to use when fitting songs.
+    pub min_size: f32,
+    /// The largest number of columns to use when fitting songs.
+    pub fit_columns: u8,
+    /// Where to place the chord diagrams of each song.
+    pub diagrams: Diagrams,
+}
+
+impl RenderOptions {
+    /// The known chords for the instrument, tuning and chord db.
+    pub fn chords(&self) -> ChordHolder {
+        let chords = ChordHolder::new_for(self.instrument);
+        let chords = match &self.tuning {
+            Some(tuning) => chords.with_tuning(tuning),
+            None => chords,
+        };
+        chords.with_library(&self.chord_db)
+    }
+}
+
+/// Parse a chopro source and render all songs in it.
+///
+/// Each song starts on a new page, the first one on `page`.
+/// The chords used in the songs are added to `appendix`.
+/// Returns the last page used, or None if the source has no songs
+/// (and `page` is still unused).
+pub fn render_source<R: io::Read>(
+    document: &mut Pdf,
+    source: R,
+    songfilename: &str,
+    page: PageDim,
+    options: &RenderOptions,
+    appendix: &mut ChordHolder,
+) -> io::Result<Option<PageDim>> {
+    let diagnostics = Diagnostics::new(songfilename);
+    let path = Path::new(songfilename);
+    let songs = Song::parse(source, path, &diagnostics, &options.selectors);
+    let mut last = None;
+    for mut song in songs {
+        let page = last.map_or(page, |last: PageDim| last.next());
+        song.transpose(options.transpose);
+        last = Some(render_song(
+            document,
+            song,
+            songfilename,
+            page,
+            options,
+            &diagnostics,
+            appendix,
+        )?);
+    }
+    Ok(last)
+}
+
+#[test]
+fn test_render_empty_source() {
+    use crate::pagedim::Paper;
+    let file = std::env::temp_dir()
+        .join(format!("chord3-test-empty-{}.pdf", std::process::id()));
+    let mut document = Pdf::create(file.to_str().unwrap()).unwrap();
+    let options = RenderOptions::default();
+    let mut appendix = options.chords();
+    let page = PageDim::new(Paper::A4, false, true, true);
+    let mut render = |source: &str, page| {
+        let source = source.as_bytes();
+        render_source(&mut document, source, "t", page, &options, &mut appendix)
+            .unwrap()
+    };
+    assert_eq!(render("", page).map(|p| p.pageno()), None);
+    assert_eq!(render("# Just a comment\n", page).map(|p| p.pageno()), None);
+    let last = render("{title: One}\nla\n{new_song}\nla\n", page);
+    assert_eq!(last.map(|p| p.pageno()), Some(Some(2)));
+    document.finish().unwrap();
+    std::fs::remove_file(&file).unwrap();
+}
+
+/// Render a song, starting on `page`.
+///
+/// The chords used in the song are added to `appendix`.
+/// Returns the last page used.
+pub fn render_song(
+    document: &mut Pdf,
+    song: Song,
+    songfilename: &str,
+    page: PageDim,
+    options: &RenderOptions,
+    diagnostics: &Diagnostics,
+    appendix: &mut ChordHolder,
+) -> io::Result<PageDim> {
+    let mut state = SongState::new(options, diagnostics);
+    state.set_capo(song.pos, &song.meta);
+    for def in song.chord_defs {
+        state.define(def.pos, def.name, def.def, def.fingers);
+    }
+    state.use_chords(&song.body)?;
+    let meta = song.meta;
+    let header = meta.format(&options.header);
+    let header_height = |base_size| {
+        let title = if song.title.is_some() { 1.5 } else { 0. };
+        let header = if header.is_empty() { 0. } else { 1.5 };
+        (title + header) * base_size + 16. * song.subtitles.len() as f32
+    };
+    let (base_size, n_cols) =
+        match fit_song(&song.body, &header_height, page, &mut state, options) {
+            Some(fit) => fit,
+            None => (options.base_size, 1),
+        };
+    let top = page.top() - header_height(base_size);
+    let pages =
+        layout_song(song.body, top, page, &mut state, base_size, n_cols);
+    let boxes_page = match options.diagrams {
+        Diagrams::Bottom => pages.len() - 1,
+        _ => 0,
+    };
+    let n_pages = pages.len();
+    let mut title = Some((song.title, song.subtitles));
+    let mut page = page;
+    for (i, placed) in pages.into_iter().enumerate() {
+        if i > 0 {
+            page = page.next();
+        }
+        document.render_page(page.width(), page.height(), |c| {
+            if options.show_sourcename {
+                if page.is_verso() {
+                    c.right_text(
+                        page.right(),
+                        page.bottom(),
+                        BuiltinFont::Helvetica_Oblique,
+                        10. * page.scale(),
+                        songfilename,
+                    )?;
+                } else {
+                    c.left_text(
+                        page.left(),
+                        page.bottom(),
+                        BuiltinFont::Helvetica_Oblique,
+                        10. * page.scale(),
+                        songfilename,
+                    )?;
+                }
+            }
+            write_pageno(c, &page)?;
+            if let Some((title, subtitles)) = title.take() {
+                let (mut y, left) = (page.top(), page.left());
+                if let Some(title) = title {
+                    y = render_title(c, &title, y, left, base_size)?;
+                }
+                for subtitle in subtitles {
+                    y = render_subtitle(c, &subtitle, y, left)?;
+                }
+                render_header(c, &header, y, left, base_size)?;
+            }
+            let mut box_w = 0.;
+            for Placed {
+                token,
+                y,
+                left,
+                right,
+            } in placed
+            {
+                state.right = right;
+                render_token(
+                    token, y, left, c, &mut state, base_size, &mut box_w,
+                )?;
+            }
+            let source = options.show_sourcename.then_some(songfilename);
+            write_copyright(c, &page, &meta, source)?;
+            if i + 1 == n_pages {
+                state.report_unknown_chords();
+            }
+            if i == boxes_page {
+                let chords = &state.chords;
+                let used = chords.get_used();
+                let boxes = options
+                    .diagrams
+                    .place(&page, top, &used, chords, base_size);
+                if let Some((placement, _)) = boxes {
+                    render_placed_chordboxes(
+                        c, &placement, used, chords, base_size,
+                    )?;
+                }
+            }
+            Ok(())
+        })?;
+    }
+    appendix.use_all(&state.chords);
+    Ok(page)
+}
+
+/// Parse the first song of `source`, for tests.
+#[cfg(test)]
+fn song(source: &str) -> Song {
+    let diagnostics = Diagnostics::new("test");
+    Song::parse(source.as_bytes(), Path::new("test"), &diagnostics, &[])
+        .remove(0)
+}
+
+#[test]
+fn test_fit_song() {
+    use crate::pagedim::Paper;
+    let body = song(&format!("{{title: Long}}\n{}", "la la\n".repeat(80))).body;
+    let diagnostics = Diagnostics::new("test");
+    let recto = PageDim::new(Paper::A4, false, true, true);
+    let fit = |page, fit, min_size, fit_columns| {
+        let options = RenderOptions {
+            base_size: 12.,
+            fit,
+            min_size,
+            fit_columns,
+            ..RenderOptions::default()
+        };
+        let mut song = SongState::new(&options, &diagnostics);
+        fit_song(&body, &|size| 1.5 * size, page, &mut song, &options)
+    };
+    assert_eq!(fit(recto, Fit::None, 8., 1), None);
+    assert_eq!(fit(recto, Fit::Page, 8., 1), Some((8.5, 1)));
+    assert_eq!(fit(recto, Fit::Page, 10., 1), None);
+    assert_eq!(fit(recto, Fit::Page, 10., 2), Some((12., 2)));
+    assert_eq!(fit(recto, Fit::Pair, 10., 1), None);
+    assert_eq!(fit(recto.next(), Fit::Pair, 10., 1), Some((12., 1)));
+}
+
+#[test]
+fn test_layout_chordboxes() {
+    use crate::pagedim::Paper;
+    let chords = "[C]la [D]la [E]la [F]la [G]la [A]la [B]la [Am]la [Dm]la \
+                  [Em]la [Bm]la [C7]la [D7]la [E7]la [G7]la\n";
+    let diagnostics = Diagnostics::new("test");
+    let page = PageDim::new(Paper::A4, false, false, true);
+    let options = RenderOptions::default();
+    for n_lines in (10..60).step_by(7) {
+        let body = song(&format!("{chords}{}", "la la\n".repeat(n_lines))).body;
+        let mut song = SongState::new(&options, &diagnostics);
+        song.use_chords(&body).unwrap();
+        let pages = layout_song(body, page.top(), page, &mut song, 12., 1);
+        let last = (1..pages.len()).fold(page, |p, _| p.next());
+        let boxes_top = {
+            let used = song.chords.get_used();
+            let boxes =
+                Diagrams::Bottom.place(&last, 0., &used, &song.chords, 12.);
+            boxes.unwrap().0.top
+        };
+        assert!(boxes_top > page.bottom() + 100.);
+        for p in pages.last().unwrap() {
+            song.right = p.right;
+            let bottom = p.y - token_height(&p.token, p.left, &song, 12.);
+            assert!(bottom >= boxes_top, "{n_lines} lines: {bottom}");
+        }
+    }
+}
+
+#[test]
+fn test_layout_diagrams() {
+    use crate::pagedim::Paper;
+    let source = format!("[C]la [D]la [E]la [F]la\n{}", "la la\n".repeat(120));
+    let body = song(&source).body;
+    let diagnostics = Diagnostics::new("test");
+    let page = PageDim::new(Paper::A4, false, false, true);
+    let top = page.top();
+    let layout = |diagrams| {
+        let options = RenderOptions {
+            diagrams,
+            ..RenderOptions::default()
+        };
+        let mut song = SongState::new(&options, &diagnostics);
+        song.use_chords(&body).unwrap();
+        let used = song.chords.get_used();
+        let boxes = diagrams.place(&page, top, &used, &song.chords, 12.);
+        let placement = boxes.map(|(placement, _)| placement);
+        drop(used);
+        let pages = layout_song(body.clone(), top, page, &mut song, 12., 1);
+        (placement, pages)
+    };
+    let (placement, pages) = layout(Diagrams::Top);
+    let grid_bottom = top - placement.unwrap().grid.height();
+    assert!(pages[0].iter().all(|p| p.y <= grid_bottom));
+    assert!(pages[1].iter().any(|p| p.y > grid_bottom));
+
+    let (placement, pages) = layout(Diagrams::Side);
+    let placement = placement.unwrap();
+    assert_eq!(placement.grid.n_aside, 1);
+    let boxes_left = placement.right - placement.grid.box_width;
+    assert!(pages[0].iter().all(|p| p.right < boxes_left));
+    assert!(pages[1].iter().any(|p| p.right > boxes_left));
+
+    let (placement, pages) = layout(Diagrams::First);
+    let boxes_top = placement.unwrap().top;
+    assert!(pages[0].iter().all(|p| p.y > boxes_top + 12.));
+    assert!(pages[1].iter().any(|p| p.y < boxes_top));
+
+    let (placement, pages) = layout(Diagrams::Appendix);
+    assert!(placement.is_none());
+    assert_eq!(pages.len(), 3);
+}
+
+#[test]
+fn test_layout_pages() {
+    use crate::pagedim::Paper;
+    let diagnostics = Diagnostics::new("test");
+    let page = PageDim::new(Paper::A4, false, false, true);
+    let options = RenderOptions::default();
+    let layout = |source: &str| {
+        let body = song(source).body;
+        let mut song = SongState::new(&options, &diagnostics);
+        layout_song(body, page.top(), page, &mut song, 12., 1)
+    };
+    let chorus = format!("{{soc}}\n{}{{eoc}}\n", "la la\n".repeat(10));
+    let source =
+        format!("{}{{columns: 2}}\n{}", "la\n".repeat(45), chorus.repeat(24));
+    let pages = layout(&source);
+    assert!(pages.len() > 2);
+    // Each column on the following pages starts at the top.
+    for placed in &pages[1..pages.len() - 1] {
+        let tops = placed
+            .iter()
+            .filter(|p| placed.iter().all(|q| q.left != p.left || q.y <= p.y))
+            .map(|p| p.y)
+            .collect::<Vec<_>>();
+        assert_eq!(tops, vec![page.top(); 2]);
+    }
+    let pages = layout("{columns: 2}\nla\n{new_page}\nla\n");
+    assert_eq!(pages.len(), 2);
+}
+
+/// A token placed in a column by `layout_song`.
+struct Placed {
+    token: Token,
+    /// The top of the token.
+    y: f32,
+    /// The left edge of the column.
+    left: f32,
+    /// The right edge of the column.
+    right: f32,
+}
+
+/// Lay out the body of a song in columns on pages, leaving room for
+/// the chord boxes where `Diagrams` places them.
+///
+/// The first page starts at `top`, below the song header, with at
+/// least `min_columns` columns.
+/// Returns the tokens placed on each page.
+fn layout_song(
+    body: Vec<Token>,
+    top: f32,
+    page: PageDim,
+    state: &mut SongState,
+    base_size: f32,
+    min_columns: u8,
+) -> Vec<Vec<Placed>> {
+    let diagrams = state.options.diagrams;
+    let reserved = {
+        let (chords, used) = (&state.chords, state.chords.get_used());
+        diagrams
+            .place(&page, top, &used, chords, base_size)
+            .map(|(_, reserved)| reserved)
+            .unwrap_or_default()
+    };
+    if diagrams != Diagrams::Bottom {
+        let reserved = |i| {
+            if i == 0 {
+                reserved
+            } else {
+                Reserved::default()
+            }
+        };
+        return layout_pages(
+            body,
+            top,
+            page,
+            state,
+            base_size,
+            min_columns,
+            &reserved,
+        );
+    }
+    // The last page is not known until the song is laid out, so lay
+    // it out again with the chord boxes reserved from that page on.
+    // The pages before it are the same, and anything that does not
+    // fit above the chord boxes goes on a new last page.
+    let pages = layout_pages(
+        body.clone(),
+        top,
+        page,
+        state,
+        base_size,
+        min_columns,
+        &|_| Reserved::default(),
+    );
+    let last = pages.len() - 1;
+    let reserved = |i| {
+        if i >= last {
+            reserved
+        } else {
+            Reserved::default()
+        }
+    };
+    layout_pages(body, top, page, state, base_size, min_columns, &reserved)
+}
+
+/// Lay out the body of a song in columns on pages.
+///
+/// A token that does not fit in the rest of a column is moved to the
+/// next column or page, or split if it would not fit in a column
+/// anyway.
+/// The space given by `reserved` for each page (by index from the
+/// first page of the song) is left empty.
+fn layout_pages(
+    body: Vec<Token>,
+    top: f32,
+    page: PageDim,
+    state: &mut SongState,
+    base_size: f32,
+    min_columns: u8,
+    reserved: &dyn Fn(usize) -> Reserved,
+) -> Vec<Vec<Placed>> {
+    let mut pages = vec![vec![]];
+    let mut page = page;
+    let mut body = body.into_iter();
+    // The rest of a section that did not fit in a column.
+    let mut pending = None;
+    let mut last_chorus = None;
+    let mut area = BodyArea::new(&page, top, reserved(0), base_size);
+    let mut column_top = area.top;
+    let mut y = area.top;
+    let mut left = area.left;
+    let mut n_cols = min_columns;
+    let mut empty = true;
+    loop {
+        let mut next_page = false;
+        let token = if let Some(token) = pending.take() {
+            token
+        } else if let Some(token) = body.next() {
+            state.recall(token, &mut last_chorus)
+        } else {
+            break;
+        };
+        let limit = area.limit;
+        let next_column = match token.expr {
+            ChordFileExpression::StartColumns { n_columns } => {
+                column_top = y;
+                n_cols = n_columns.max(min_columns);
+                continue;
+            }
+            ChordFileExpression::ColumnBreak => true,
+            ChordFileExpression::PageBreak => {
+                next_page = true;
+                true
+            }
+            _ => {
+                let width = area.width / f32::from(n_cols);
+                state.right = area.right.min(left + width);
+                let height = token_height(&token, left, state, base_size);
+                // Move to the next column, unless this is the top of a
+                // column or the token would not fit in a column anyway.
+                if y - height < limit && !empty && height <= column_top - limit
+                {
+                    pending = Some(token);
+                    true
+                } else {
+                    let (token, height) = if y - height < limit {
+                        let (token, rest) = split_section(
+                            token,
+                            y - limit,
+                            left,
+                            state,
+                            base_size,
+                        );
+                        pending = rest;
+                        let height =
+                            token_height(&token, left, state, base_size);
+                        (token, height)
+                    } else {
+                        (token, height)
+                    };
+                    if let Some(placed) = pages.last_mut() {
+                        placed.push(Placed {
+                            token,
+                            y,
+                            left,
+                            right: state.right,
+                        });
+                    }
+                    y -= height;
+                    empty = false;
+                    pending.is_some() || y < limit
+                }
+            }
+        };
+        if next_column {
+            left += area.width / f32::from(n_cols) + 10. * page.scale();
+            empty = true;
+            if left < area.right && !next_page {
+                y = column_top;
+            } else {
+                page = page.next();
+                pages.push(vec![]);
+                let reserved = reserved(pages.len() - 1);
+                area = BodyArea::new(&page, page.top(), reserved, base_size);
+                left = area.left;
+                y = area.top;
+                column_top = area.top;
+            }
+        }
+    }
+    while pages.len() > 1 && pages.last().is_some_and(Vec::is_empty) {
+        pages.pop();
+    }
+    pages
+}
+
+/// The area of a page available for the body of a song.
+struct BodyArea {
+    top: f32,
+    left: f32,
+    right: f32,
+    width: f32,
+    /// The lowest baseline for the body.
+    limit: f32,
+}
+
+impl BodyArea {
+    /// The area below `top` on `page`, except the `reserved` space.
+    fn new(
+        page: &PageDim,
+        top: f32,
+        reserved: Reserved,
+        base_size: f32,
+    ) -> BodyArea {
+        let (left, right) = if page.is_verso() {
+            (page.left() + reserved.outer, page.right())
+        } else {
+            (page.left(), page.right() - reserved.outer)
+        };
+        BodyArea {
+            top: top - reserved.top,
+            left,
+            right,
+            width: page.inner_width() - reserved.outer,
+            limit: (page.bottom() + base_size).max(reserved.bottom),
+        }
+    }
+}
+
+/// How to fit songs on pages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum Fit {
+    /// Don't change the base size to fit songs.
+    #[default]
+    None,
+    /// Fit each song on one page.
+    Page,
+    /// Fit each song on a pair of facing pages, if it starts on a
+    /// verso page, otherwise on one page.
+    Pair,
+}
+
+/// Find the largest base size, and the least number of columns, for
+/// the song to fit (with its chord boxes) as configured by
+/// `options.fit`.
+///
+/// The base sizes tried are from `options.base_size` down to
+/// `options.min_size`, and the number of columns from the columns
+/// of the song up to `options.fit_columns`.
+/// Returns None if fitting is not enabled or the song does not fit.
+fn fit_song(
+    body: &[Token],
+    header_height: &dyn Fn(f32) -> f32,
+    page: PageDim,
+    state: &mut SongState,
+    options: &RenderOptions,
+) -> Option<(f32, u8)> {
+    let n_pages = match options.fit {
+        Fit::None => return None,
+        Fit::Pair if page.is_verso() => 2,
+        Fit::Page | Fit::Pair => 1,
+    };
+    let min_size = options.min_size.min(options.base_size);
+    for n_cols in 1..=options.fit_columns.max(1) {
+        let mut base_size = options.base_size;
+        while base_size >= min_size {
+            let top = page.top() - header_height(base_size);
+            let pages =
+                layout_song(body.to_vec(), top, page, state, base_size, n_cols);
+            if pages.len() <= n_pages {
+                return Some((base_size, n_cols));
+            }
+            base_size -= 0.5;
+        }
+    }
+    None
+}
+
+/// State of the song currently being rendered (or checked).
+pub(crate) struct SongState<'a> {
+    pub(crate) chords: ChordHolder,
+    /// The label and lines of the last chorus, for `{chorus}`.
+    pub(crate) last_chorus: Option<(Option<String>, Vec<Token>)>,
+    /// The right edge of the current column, where lines are wrapped.
+    pub(crate) right: f32,
+    options: &'a RenderOptions,
+    diagnostics: &'a Diagnostics,
+}
+
+impl<'a> SongState<'a> {
+    pub(crate) fn new(
+        options: &'a RenderOptions,
+        diagnostics: &'a Diagnostics,
+    ) -> Self {
+        SongState {
+            chords: options.chords(),
+            last_chorus: None,
+            right: f32::INFINITY,
+            options,
+            diagnostics,
+        }
+    }
+
+    /// Set the capo from the `{capo}` of a song starting at `pos`.
+    pub(crate) fn set_capo(&mut self, pos: Pos, meta: &Metadata) {
+        let Some(capo) = meta.get("capo") else {
+            return;
+        };
+        match capo.trim().parse::<u8>() {
+            Ok(capo) if capo < 12 => self.chords.set_capo(capo),
+            _ => self.diagnostics.warning(
+                pos,
+                Code::BadCapo,
+                format_args!("Bad capo {capo:?}, expected a fret below 12"),
+            ),
+        }
+    }
+
+    /// The name to show for a chord, as configured for capo chords.
+    pub(crate) fn chord_name(&self, chord: &str) -> String {
+        if self.chords.capo() == 0 {
+            return chord.to_string();
+        }
+        match self.options.capo_chords {
+            CapoChords::Shapes => chord.to_string(),
+            CapoChords::Sounding => self.chords.sounding(chord),
+            CapoChords::Both => {
+                let sounding = self.chords.sounding(chord);
+                if sounding == chord {
+                    sounding
+                } else {
+                    format!("{chord} ({sounding})")
+                }
+            }
+        }
+    }
+
+    /// Get the last chorus, to recall it at `pos`.
+    pub(crate) fn recall_chorus(
+        &self,
+        pos: Pos,
+    ) -> Option<&(Option<String>, Vec<Token>)> {
+        if self.last_chorus.is_none() {
+            self.diagnostics.warning(
+                pos,
+                Code::NoChorus,
+                "No previous chorus to recall",
+            );
+        }
+        self.last_chorus.as_ref()
+    }
+
+    /// Resolve a `{chorus}` to the last chorus before it, in full or
+    /// as a label, as configured for recalled choruses.
+    ///
+    /// Choruses are remembered in `last_chorus`, also when they are
+    /// inside a section, and recalls inside sections are resolved.
+    fn recall(
+        &self,
+        token: Token,
+        last_chorus: &mut Option<(Option<String>, Vec<Token>)>,
+    ) -> Token {
+        let full = self.options.chorus_recall == ChorusRecall::Full;
+        match (token.expr, &last_chorus) {
+            (ChordFileExpression::Chorus { label, lines }, _) => {
+                let lines = self.recall_all(lines, last_chorus);
+                *last_chorus = Some((label.clone(), lines.clone()));
+                Token {
+                    expr: ChordFileExpression::Chorus { label, lines },
+                    ..token
+                }
+            }
+            (ChordFileExpression::Section { kind, label, lines }, _) => {
+                let lines = self.recall_all(lines, last_chorus);
+                Token {
+                    expr: ChordFileExpression::Section { kind, label, lines },
+                    ..token
+                }
+            }
+            (
+                ChordFileExpression::ChorusRecall { label },
+                Some((last_label, lines)),
+            ) => {
+                let label = label.or_else(|| last_label.clone());
+                let expr = if full {
+                    ChordFileExpression::Chorus {
+                        label,
+                        lines: lines.clone(),
+                    }
+                } else {
+                    ChordFileExpression::ChorusRecall { label }
+                };
+                Token { expr, ..token }
+            }
+            (expr, _) => Token { expr, ..token },
+        }
+    }
+
+    fn recall_all(
+        &self,
+        tokens: Vec<Token>,
+        last_chorus: &mut Option<(Option<String>, Vec<Token>)>,
+    ) -> Vec<Token> {
+        tokens
+            .into_iter()
+            .map(|token| self.recall(token, last_chorus))
+            .collect()
+    }
+
+    /// Mark the chords of `tokens` as used, so the chord boxes of a
+    /// song are known before it is rendered.
+    fn use_chords(&mut self, tokens: &[Token]) -> io::Result<()> {
+        for token in tokens {
+            match &token.expr {
+                ChordFileExpression::Line { segments } => {
+                    self.use_line_chords(token.pos, segments);
+                }
+                ChordFileExpression::Form { keys, form, .. } => {
+                    self.use_form_chords(token.pos, keys, form)?;
+                }
+                ChordFileExpression::Chorus { lines, .. }
+                | ChordFileExpression::Section { lines, .. } => {
+                    self.use_chords(lines)?;
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn define(
+        &mut self,
+        pos: Pos,
+        name: String,
+        def: Vec<i8>,
+        fingers: Vec<u8>,
+    ) {
+        let n_frets = self.chords.n_frets();
+        if fit_shape(&def, n_frets).is_none() {
+            self.diagnostics.warning(
+                pos,
+                Code::BadChordDef,
+                format_args!(
+                    "Ignoring chord def {name}, it spans more than {n_frets} \
+                     frets"
+                ),
+            );
+        } else if !self.chords.define(name.clone(), def, fingers) {
+            self.diagnostics.warning(
+                pos,
+                Code::WrongInstrument,
+                format_args!("Ignoring chord def {name}, wrong instrument"),
+            );
+        }
+    }
+
+    /// Mark the chords of a `Line` as used.
+    pub(crate) fn use_line_chords(&mut self, pos: Pos, segments: &[Segment]) {
+        let mut col = 0;
+        for segment in segments {
+            if let Some(chord) = &segment.chord {
+                self.chords.use_chord(chord, pos.right(col));
+                col += chord.chars().count() + 2;
+            }
+            col += segment.lyrics.chars().count();
+        }
+    }
+
+    /// Mark the chords of a `Form`, in each of its keys, as used.
+    pub(crate) fn use_form_chords(
+        &mut self,
+        pos: Pos,
+        keys: &[String],
+        form: &[Vec<String>],
+    ) -> io::Result<()> {
+        for key in keys {
+            let key = Key::new(key)?;
+            for chord in form.iter().flatten() {
+                for chord in chord.split_ascii_whitespace() {
+                    self.chords.use_chord(&key.from_nashville(chord), pos);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn stray_end(&self, pos: Pos, what: &str) {
+        self.diagnostics.warning(
+            pos,
+            Code::StrayEnd,
+            format_args!("Stray end of {what} in song"),
+        );
+    }
+
+    /// Report a `{columns}` inside a chorus or section, where it is
+    /// ignored.
+    pub(crate) fn misplaced_columns(&self, pos: Pos) {
+        self.diagnostics.warning(
+            pos,
+            Code::BadColumns,
+            "Columns inside a chorus or section are ignored",
+        );
+    }
+
+    pub(crate) fn report_unknown_chords(&self) {
+        for (name, pos) in self.chords.get_unknown() {
+            match name.parse::<Chord>() {
+                Ok(_) => self.diagnostics.warning(
+                    pos,
+                    Code::UnknownChord,
+                    format_args!("Unknown chord {name}"),
+                ),
+                Err(err) => self.diagnostics.warning(
+                    pos,
+                    Code::UnknownChord,
+                    format_args!("Unknown chord {name}: {err}"),
+                ),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_recall_in_section() {
+    let options = RenderOptions {
+        chorus_recall: ChorusRecall::Full,
+        ..RenderOptions::default()
+    };
+    let body = song(
+        "{title: Test}\n\
+         {sov}\nla\n{soc: Refrain}\nla\nla\n{eoc}\n{eov}\n\
+         {sov}\nla\n{chorus}\n{eov}\n",
+    )
+    .body;
+    let diagnostics = Diagnostics::new("test");
+    let song = SongState::new(&options, &diagnostics);
+    let mut last_chorus = None;
+    let body = song.recall_all(body, &mut last_chorus);
+    let ChordFileExpression::Section { lines, .. } = &body[1].expr else {
+        panic!("Expected a section");
+    };
+    let ChordFileExpression::Chorus { label, lines } = &lines[1].expr else {
+        panic!("Expected a recalled chorus");
+    };
+    assert_eq!((label.as_deref(), lines.len()), (Some("Refrain"), 2));
+    assert_eq!(diagnostics.n_warnings(), 0);
+}
+
+/// Render the title of a song, and add it to the document outline.
+fn render_title(
+    c: &mut Canvas<'_>,
+    title: &str,
+    y: f32,
+    left: f32,
+    base_size: f32,
+) -> io::Result<f32> {
+    c.add_outline(title);
+    let y = y - 1.5 * base_size;
+    let font = BuiltinFont::Times_Bold;
+    c.left_text(left, y, font, base_size * 4. / 3., title)?;
+    Ok(y)
+}
+
+fn render_subtitle(
+    c: &mut Canvas<'_>,
+    subtitle: &str,
+    y: f32,
+    left: f32,
+) -> io::Result<f32> {
+    let y = y - 16.0;
+    c.left_text(left, y, BuiltinFont::Times_Italic, 14.0, subtitle)?;
+    Ok(y)
+}
+
+/// Render the metadata header line, if it is not empty.
+fn render_header(
+    c: &mut Canvas<'_>,
+    header: &str,
+    y: f32,
+    left: f32,
+    base_size: f32,
+) -> io::Result<f32> {
+    if header.is_empty() {
+        return Ok(y);
+    }
+    let y = y - 1.2 * base_size;
+    c.left_text(left, y, BuiltinFont::Times_Italic, base_size, header)?;
+    Ok(y - 0.3 * base_size)
+}
+
+/// Write the copyright of the song, if any, in the page footer.
+///
+/// The copyright is centered on the page, but moved away from the
+/// page number and the source name (if shown) if it would overlap
+/// them, and shortened if it does not fit between them.
+fn write_copyright(
+    c: &mut Canvas,
+    page: &PageDim,
+    meta: &Metadata,
+    source: Option<&str>,
+) -> io::Result<()> {
+    if let Some(copyright) = meta.get("copyright") {
+        let font = BuiltinFont::Times_Italic;
+        let size = 10. * page.scale();
+        let (left, right) = footer_space(page, source);
+        let copyright = shorten(font, size, &copyright, right - left);
+        let half = font.get_width(size, &copyright) / 2.;
+        let center = ((page.left() + page.right()) / 2.)
+            .min(right - half)
+            .max(left + half);
+        c.center_text(center, page.bottom(), font, size, &copyright)?;
+    }
+    Ok(())
+}
+
+/// The free space in the page footer, between the page number and the
+/// source name (if shown), as left and right edges.
+fn footer_space(page: &PageDim, source: Option<&str>) -> (f32, f32) {
+    let scale = page.scale();
+    let gap = 10. * scale;
+    let pageno = page.pageno().map_or(0., |pageno| {
+        let pageno = format!("{pageno}");
+        BuiltinFont::Times_Italic.get_width(12. * scale, &pageno) + gap
+    });
+    let source = source.map_or(0., |source| {
+        BuiltinFont::Helvetica_Oblique.get_width(10. * scale, source) + gap
+    });
+    if page.is_verso() {
+        (page.left() + pageno, page.right() - source)
+    } else {
+        (page.left() + source, page.right() - pageno)
+    }
+}
+
+/// Shorten `text` with an ellipsis, if needed to fit in `width`.
+fn shorten(font: BuiltinFont, size: f32, text: &str, width: f32) -> String {
+    if font.get_width(size, text) <= width {
+        return text.to_string();
+    }
+    let mut text = text.to_string();
+    while text.pop().is_some() {
+        let short = format!("{}…", text.trim_end());
+        if font.get_width(size, &short) <= width {
+            return short;
+        }
+    }
+    String::new()
+}
+
+#[test]
+fn test_footer_copyright() {
+    use crate::pagedim::Paper;
+    let page = PageDim::new(Paper::A5, false, true, true);
+    let (left, right) = footer_space(&page, Some("a-long-source-name.chopro"));
+    assert!(left > page.left() + 50.);
+    assert!(right < page.right() - 10.);
+    let font = BuiltinFont::Times_Italic;
+    let text = "Copyright 1965 by Some Music Publishing Company, Inc.";
+    assert_eq!(shorten(font, 10., text, right - left), text);
+    let short = shorten(font, 10., text, 150.);
+    assert_eq!(short, "Copyright 1965 by Some Music Pu…");
+    assert!(font.get_width(10., &short) <= 150.);
+    assert_eq!(shorten(font, 10., text, 1.), "");
+}
+
+fn write_pageno(c: &mut Canvas, page: &PageDim) -> io::Result<()> {
+    if let Some(pageno) = page.pageno() {
+        let font = BuiltinFont::Times_Italic;
+        let pageno = format!("{pageno}");
+        let (y, size) = (page.bottom(), 12. * page.scale());
+        if page.is_verso() {
+            c.left_text(page.left(), y, font, size, &pageno)?;
+        } else {
+            c.right_text(page.right(), y, font, size, &pageno)?;
+        }
+    }
+    Ok(())
+}
+
+/// Where to place the chord diagrams of each song.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum Diagrams {
+    /// In rows at the bottom of the last page of the song.
+    #[default]
+    Bottom,
+    /// In rows at the bottom of the first page of the song.
+    First,
+    /// In rows under the title of the song.
+    Top,
+    /// In a narrow column along the outer margin of the first page.
+    Side,
+    /// Only in an appendix with the chords of all songs, at the end of
+    /// the book.
+    Appendix,
+}
+
+impl Diagrams {
+    /// Place the chord boxes for `used_chords` on `page`, where `top`
+    /// is the top of the song body (below the header).
+    ///
+    /// Returns the placement and the space to reserve for it on the
+    /// page, or None if there are no chord boxes to show in the song.
+    fn place(
+        self,
+        page: &PageDim,
+        top: f32,
+        used_chords: &[(&str, &Vec<i8>)],
+        chords: &ChordHolder,
+        base_size: f32,
+    ) -> Option<(Placement, Reserved)> {
+        let max_height = page.height() / 2.;
+        match self {
+            Diagrams::Bottom | Diagrams::First => {
+                let width = page.inner_width();
+                let grid = BoxGrid::new(width, used_chords, chords, base_size)?;
+                let top = page.bottom() / 2. + grid.height();
+                let reserved = Reserved {
+                    bottom: (top + 0.5 * base_size).min(max_height),
+                    ..Reserved::default()
+                };
+                Some((Placement::new(grid, page.right(), top), reserved))
+            }
+            Diagrams::Top => {
+                let width = page.inner_width();
+                let grid = BoxGrid::new(width, used_chords, chords, base_size)?;
+                let reserved = Reserved {
+                    top: grid.height().min(max_height),
+                    ..Reserved::default()
+                };
+                Some((Placement::new(grid, page.right(), top), reserved))
+            }
+            Diagrams::Side => {
+                let height = top - page.bottom() / 2.;
+                let max_width = page.inner_width() / 2.;
+                let grid = BoxGrid::column(
+                    max_width,
+                    height,
+                    used_chords,
+                    chords,
+                    base_size,
+                )?;
+                let width = grid.width(chords, base_size).min(max_width);
+                let right = if page.is_verso() {
+                    page.left() + width
+                } else {
+                    page.right()
+                };
+                let reserved = Reserved {
+                    outer: width + 10. * page.scale(),
+                    ..Reserved::default()
+                };
+                Some((Placement::new(grid, right, top), reserved))
+            }
+            Diagrams::Appendix => None,
+        }
+    }
+}
+
+/// Space reserved for chord boxes on a page, that the song body
+/// should not use.
+#[derive(Clone, Copy, Default)]
+struct Reserved {
+    /// The height reserved at the top of the body.
+    top: f32,
+    /// The lowest baseline for the body, if above the footer.
+    bottom: f32,
+    /// The width reserved at the outer margin.
+    outer: f32,
+}
+
+/// A grid of chord boxes, placed on a page.
+struct Placement {
+    grid: BoxGrid,
+    /// The right edge of the grid.
+    right: f32,
+    /// The top of the grid, including the chord names (and capo note)
+    /// above the boxes.
+    top: f32,
+}
+
+impl Placement {
+    fn new(grid: BoxGrid, right: f32, top: f32) -> Placement {
+        Placement { grid, right, top }
+    }
+}
+
+/// Render chordboxes for `used_chords` at the bottom of the page.
+///
+/// Fingers for the chords are taken from `chords`.
+fn render_chordboxes(
+    c: &mut Canvas<'_>,
+    page: PageDim,
+    used_chords: Vec<(&str, &Vec<i8>)>,
+    chords: &ChordHolder,
+    base_size: f32,
+) -> io::Result<()> {
+    let width = page.inner_width();
+    let Some(grid) = BoxGrid::new(width, &used_chords, chords, base_size)
+    else {
+        return Ok(());
+    };
+    let top = page.bottom() / 2. + grid.height();
+    let placement = Placement::new(grid, page.right(), top);
+    render_placed_chordboxes(c, &placement, used_chords, chords, base_size)
+}
+
+/// Render chordboxes for `used_chords` in a grid as `placement`.
+///
+/// Fingers for the chords are taken from `chords`.
+fn render_placed_chordboxes(
+    c: &mut Canvas<'_>,
+    placement: &Placement,
+    used_chords: Vec<(&str, &Vec<i8>)>,
+    chords: &ChordHolder,
+    base_size: f32,
+) -> io::Result<()> {
+    let Placement { grid, right, top } = placement;
+    let n_first = grid.n_chords - (grid.n_rows - 1) * grid.n_aside;
+    let mut x = right - n_first as f32 * grid.box_width;
+    let mut y = top - grid.head;
+    if let Some(note) = capo_note(chords) {
+        c.right_text(
+            *right,
+            y + 2. * base_size,
+            BuiltinFont::Helvetica_Oblique,
+            base_size * 5. / 6.,
+            &note,
+        )?;
+    }
+    for (chord, chorddef) in used_chords {
+        chordbox(
+            c,
+            x + base_size * 1.25,
+            y,
+            chord,
+            chorddef,
+            chords,
+            base_size,
+        )?;
+        x += grid.box_width;
+        if x >= *right {
+            x = right - grid.n_aside as f32 * grid.box_width;
+            y -= grid.box_height;
+        }
+    }
+    Ok(())
+}
+
+/// The note above chord boxes that shows shapes for a capo.
+fn capo_note(chords: &ChordHolder) -> Option<String> {
+    let capo = chords.capo();
+    (capo > 0).then(|| format!("Shapes for capo {capo}"))
+}
+
+/// The rows of chord boxes for a song.
+struct BoxGrid {
+    n_chords: u32,
+    /// The number of chord boxes in each row.
+    n_aside: u32,
+    n_rows: u32,
+    box_width: f32,
+    box_height: f32,
+    /// The height of the chord names (and capo note) above the boxes.
+    head: f32,
+}
+
+impl BoxGrid {
+    /// The grid for `used_chords` in rows of `width`, or None if there
+    /// are no chord boxes.
+    fn new(
+        width: f32,
+        used_chords: &[(&str, &Vec<i8>)],
+        chords: &ChordHolder,
+        base_size: f32,
+    ) -> Option<BoxGrid> {
+        let box_width = BoxGrid::min_box_width(used_chords, chords, base_size)?;
+        let n_bands = chords.n_frets();
+        let (_, dy) = box_spacing(n_bands, base_size);
+        let n_chords = used_chords.len() as u32;
+        let n_aside = ((width / box_width) as u32).max(1);
+        let head = if chords.capo() > 0 { 3. } else { 1.5 };
+        Some(BoxGrid {
+            n_chords,
+            n_aside,
+            n_rows: n_chords.div_ceil(n_aside),
+            box_width: (width + 7.0) / n_aside as f32,
+            box_height: (f32::from(n_bands) + 0.4) * dy + base_size * 2.6,
+            head: head * base_size,
+        })
+    }
+
+    /// The narrowest grid for `used_chords` that fits in `height`, at
+    /// most `max_width` wide.
+    fn column(
+        max_width: f32,
+        height: f32,
+        used_chords: &[(&str, &Vec<i8>)],
+        chords: &ChordHolder,
+        base_size: f32,
+    ) -> Option<BoxGrid> {
+        let box_width = BoxGrid::min_box_width(used_chords, chords, base_size)?;
+        let max_aside = ((max_width / box_width) as u32).max(1);
+        (1..=max_aside)
+            .map(|n| {
+                let width = n as f32 * box_width;
+                BoxGrid::new(width, used_chords, chords, base_size)
+            })
+            .find(|grid| grid.as_ref().is_some_and(|g| g.height() <= height))
+            .unwrap_or_else(|| {
+                let width = max_aside as f32 * box_width;
+                BoxGrid::new(width, used_chords, chords, base_size)
+            })
+    }
+
+    /// The smallest width of a chord box for `used_chords`, or None if
+    /// there are no chord boxes.
+    fn min_box_width(
+        used_chords: &[(&str, &Vec<i8>)],
+        chords: &ChordHolder,
+        base_size: f32,
+    ) -> Option<f32> {
+        let n_strings = match used_chords.first().map(|v| v.1.len()) {
+            Some(0) | None => return None,
+            Some(len) => (len - 1) as f32,
+        };
+        let (dx, _) = box_spacing(chords.n_frets(), base_size);
+        Some(
+            ((n_strings - 1.) * dx + base_size * 17. / 12.).max(base_size * 3.),
+        )
+    }
+
+    /// The width used by the grid, including any capo note.
+    fn width(&self, chords: &ChordHolder, base_size: f32) -> f32 {
+        let boxes = self.n_aside as f32 * self.box_width - 7.;
+        let note = capo_note(chords).map_or(0., |note| {
+            BuiltinFont::Helvetica_Oblique.get_width(base_size * 5. / 6., &note)
+        });
+        boxes.max(note)
+    }
+
+    /// The height of the grid, including the chord names (and capo
+    /// note) above the boxes.
+    fn height(&self) -> f32 {
+        self.n_rows as f32 * self.box_height + self.head
+    }
+}
+
+/// The distance between strings and between frets in a chord box.
+///
+/// More frets are shown for short-necked instruments, with shorter
+/// distance between the frets.
+fn box_spacing(n_bands: u8, base_size: f32) -> (f32, f32) {
+    match n_bands {
+        0..=4 => (0.416_666_66 * base_size, 0.583_333_3 * base_size),
+        5..=6 => (0.5 * base_size, 0.5 * base_size),
+        _ => (0.458_333_34 * base_size, 0.458_333_34 * base_size),
+    }
+}
+
+fn render_token(
+    token: Token,
+    y: f32,
+    left: f32,
+    c: &mut Canvas<'_>,
+    song: &mut SongState,
+    base_size: f32,
+    box_w: &mut f32,
+) -> io::Result<f32> {
+    let times_italic = c.get_font(BuiltinFont::Times_Italic);
+    let chordfont = c.get_font(BuiltinFont::Helvetica_Oblique);
+    let tabfont = c.get_font(BuiltinFont::Courier);
+
+    let chord_size = 0.82 * base_size;
+
+    let Token { pos, expr, .. } = token;
+    match expr {
+        ChordFileExpression::Comment { s }
+        | ChordFileExpression::Unknown { s } => c.text(|t| {
+            let y = y - base_size;
+            t.set_font(&times_italic, base_size)?;
+            t.pos(left, y)?;
+            t.show(&s)?;
+            Ok(y)
+        }),
+        ChordFileExpression::Chorus { label, lines } => {
+            song.last_chorus = Some((label.clone(), lines.clone()));
+            let (first, mut y2) =
+                render_lines(lines, y, left + 10.0, c, song, base_size, box_w)?;
+            if let Some(label) = label {
+                render_label(c, &label, left - 10.0, first, base_size)?;
+            }
+            y2 -= 4.0;
+            chorus_bar(c, left, y, y2)?;
+            Ok(y2)
+        }
+        ChordFileExpression::ChorusRecall { label } => {
+            let last = song.recall_chorus(pos);
+            let label = label
+                .or_else(|| last.and_then(|(label, _)| label.clone()))
+                .unwrap_or_else(|| "Chorus".into());
+            let y2 = y - 1.1 * base_size;
+            c.left_text(
+                left + 10.0,
+                y2,
+                BuiltinFont::Times_Italic,
+                base_size,
+                &label,
+            )?;
+            let y2 = y2 - 0.3 * base_size - 4.0;
+            chorus_bar(c, left, y, y2)?;
+            Ok(y2)
+        }
+        ChordFileExpression::EndOfChorus => {
+            song.stray_end(pos, "chorus");
+            Ok(y)
+        }
+        ChordFileExpression::Section { kind, label, lines } => {
+            let (first, y) = render_lines(
+                lines,
+                y,
+                left + section_indent(&kind, base_size),
+                c,
+                song,
+                base_size,
+                box_w,
+            )?;
+            if let Some(label) = label {
+                render_label(c, &label, left - 4.0, first, base_size)?;
+            }
+            Ok(y)
+        }
+        ChordFileExpression::EndOfSection { kind } => {
+            song.stray_end(pos, kind.name());
+            Ok(y)
+        }
+        ChordFileExpression::Tab { lines } => c.text(|t| {
+            let size = base_size / 1.2;
+            let mut y = y;
+            t.pos(left, y)?;
+            t.set_font(&tabfont, size)?;
+            t.set_leading(size)?;
+            for line in lines {
+                y -= size;
+                t.show_line(&line)?;
+            }
+            Ok(y)
+        }),
+        ChordFileExpression::Form { name, keys, form } => c.text(|t| {
+            song.use_form_chords(pos, &keys, &form)?;
+            t.gsave()?;
+            let mut y = y - base_size;
+            t.set_font(&times_italic, chord_size)?;
+            t.pos(left, y)?;
+            t.show(&name)?;
+
+            t.set_fill_color(Color::gray(96))?;
+            t.set_font(&chordfont, chord_size)?;
+            let leading = chord_size * 1.2;
+            // TODO: Needs more if multiple chords in same measure
+
+            let s_w = chordfont.get_width(chord_size, " ");
+            let sl_w = chordfont.get_width(chord_size, "/ ");
+            let key = keys.first().map(|k| Key::new(k))
+                .unwrap_or(Key::new("Bb"))?;
+            let measure_w = form
+                .iter()
+                .flat_map(|l| l.iter())
+                .map(|m| {
+                    m.split_ascii_whitespace()
+                        .map(|c| {
+                            let chord = key.from_nashville(c);
+                            let chord = song.chord_name(&chord);
+                            chordfont.get_width(chord_size, &chord) + s_w
+                        })
+                        .sum()
+                })
+                .fold(0_f32, |a, b| a.max(b))
+                + sl_w;
+
+            let n_measures = form.iter().map(|l| l.len()).max().unwrap_or(1);
+            *box_w = box_w.max(measure_w * (n_measures as f32 + 1.666_666_7));
+            t.set_leading(leading)?;
+            for line in &form {
+                if let Some((first, rest)) = line.split_first() {
+                    t.pos(0., -leading)?;
+                    write_chord(t, first)?;
+                    for chord in rest {
+                        t.pos(measure_w - sl_w, 0.)?;
+                        t.show("| ")?;
+                        t.pos(sl_w, 0.)?;
+                        write_chord(t, chord)?;
+                    }
+                    for key in &keys {
+                        t.pos(*box_w - measure_w * rest.len() as f32, 0.)?;
+                        let key = Key::new(key)?;
+                        for chord in first.split_ascii_whitespace() {
+                            let chord = key.from_nashville(chord);
+                            write_chord(t, &song.chord_name(&chord))?;
+                        }
+                        for chord in rest {
+                            t.pos(measure_w - sl_w, 0.)?;
+                            t.show("| ")?;
+                            t.pos(sl_w, 0.)?;
+                            for chord in chord.split_ascii_whitespace() {
+                                let chord = key.from_nashville(chord);
+                                write_chord(t, &song.chord_name(&chord))?;
+                            }
+                        }
+                    }
+                    t.pos(
+                        measure_w
+                            - (*box_w * keys.len() as f32
+                                + measure_w * line.len() as f32),
+                        0.,
+                    )?;
+                }
+                y -= leading;
+            }
+            t.grestore()?;
+            Ok(y - leading / 2.)
+        }),
+        ChordFileExpression::EndOfTab => {
+            song.stray_end(pos, "tab");
+            Ok(y)
+        }
+        ChordFileExpression::StartColumns { .. } => {
+            // Columns at the top level are handled by `layout_song`.
+            song.misplaced_columns(pos);
+            Ok(y)
+        }
+        ChordFileExpression::ColumnBreak | ChordFileExpression::PageBreak => {
+            Ok(0.0)
+        }
+        // The song header and separators are handled by `Song`.
+        ChordFileExpression::Title { .. }
+        | ChordFileExpression::SubTitle { .. }
+        | ChordFileExpression::Meta { .. }
+        | ChordFileExpression::ChordDef { .. }
+        | ChordFileExpression::Transpose { .. }
+        | ChordFileExpression::NewSong => Ok(y),
+        // Only in verbatim mode.
+        ChordFileExpression::Include { .. }
+        | ChordFileExpression::Verbatim { .. } => Ok(y),
+        ChordFileExpression::Line { segments } => {
+            song.use_line_chords(pos, &segments);
+            let lines = wrap_segments(segments, left, song, base_size);
+            let mut y = y;
+            for (i, segments) in lines.iter().enumerate() {
+                let left = if i == 0 {
+                    left
+                } else {
+                    left + indent(base_size)
+                };
+                y = render_segments(c, segments, left, y, song, base_size)?;
+            }
+            Ok(y)
+        }
+    }
+}
+
+/// Render a line of lyrics with chords, below `y`.
+///
+/// Returns the baseline of the line.
+fn render_segments(
+    c: &mut Canvas<'_>,
+    segments: &[Segment],
+    left: f32,
+    y: f32,
+    song: &SongState,
+    base_size: f32,
+) -> io::Result<f32> {
+    let times = c.get_font(BuiltinFont::Times_Roman);
+    let chordfont = c.get_font(BuiltinFont::Helvetica_Oblique);
+    let text_size = base_size;
+    let chord_size = 0.82 * base_size;
+    c.text(|t| {
+        let y = y - line_height(segments, base_size);
+        t.set_font(&times, text_size)?;
+        t.pos(left, y)?;
+        let mut last_chord_width = 0.0;
+        for (i, segment) in segments.iter().enumerate() {
+            if let Some(chord) = &segment.chord {
+                let chord = &song.chord_name(chord);
+                t.gsave()?;
+                t.set_rise(text_size * 0.9)?;
+                t.set_fill_color(Color::gray(96))?;
+                t.set_font(&chordfont, chord_size)?;
+                let chord_width = chordfont.get_width_raw(chord) as i32;
+                t.show_adjusted(&[(chord, chord_width)])?;
+                last_chord_width =
+                    (chord_width + 400) as f32 * chord_size / 1000.0;
+                t.grestore()?;
+            }
+            let part = if segment.lyrics.is_empty() {
+                " "
+            } else {
+                &segment.lyrics
+            };
+            let text_width = times.get_width(text_size, part);
+            if last_chord_width > text_width && i + 1 < segments.len() {
+                let extra = last_chord_width - text_width;
+                let n_space = part.chars().filter(|&c| c == ' ').count();
+                if n_space > 0 {
+                    t.set_word_spacing(extra / n_space as f32)?;
+                } else {
+                    t.set_char_spacing(extra / part.len() as f32)?;
+                }
+            }
+            t.show(part)?;
+            if last_chord_width > text_width {
+                t.set_char_spacing(0.0)?;
+                t.set_word_spacing(0.0)?;
+            }
+        }
+        Ok(y)
+    })
+}
+
+/// The height of a line of lyrics, with chords if it has any.
+fn line_height(segments: &[Segment], base_size: f32) -> f32 {
+    if segments.len() == 1 {
+        1.1 * base_size
+    } else {
+        1.1 * (base_size + 0.82 * base_size)
+    }
+}
+
+/// The indentation of continuation lines of a wrapped line.
+fn indent(base_size: f32) -> f32 {
+    2. * base_size
+}
+
+/// Wrap a line of lyrics at `left` to the current column.
+fn wrap_segments(
+    segments: Vec<Segment>,
+    left: f32,
+    song: &SongState,
+    base_size: f32,
+) -> Vec<Vec<Segment>> {
+    let width = song.right - left;
+    wrap_line(segments, width, indent(base_size), |segments| {
+        line_width(segments, song, base_size)
+    })
+}
+
+/// The width of a line of lyrics with chords, as rendered by
+/// `render_segments`.
+fn line_width(segments: &[Segment], song: &SongState, base_size: f32) -> f32 {
+    let times = BuiltinFont::Times_Roman;
+    let chordfont = BuiltinFont::Helvetica_Oblique;
+    let chord_size = 0.82 * base_size;
+    let last = segments.len() - 1;
+    segments
+        .iter()
+        .enumerate()
+        .map(|(i, segment)| {
+            let part = if segment.lyrics.is_empty() {
+                " "
+            } else {
+                &segment.lyrics
+            };
+            let text_width = times.get_width(base_size, part);
+            let chord_width = segment.chord.as_ref().map_or(0., |chord| {
+                let raw = chordfont.get_width_raw(&song.chord_name(chord));
+                let space = if i < last { 400 } else { 0 };
+                (raw + space) as f32 * chord_size / 1000.0
+            });
+            text_width.max(chord_width)
+        })
+        .sum()
+}
+
+/// Break a line of segments into lines no wider than `width`.
+///
+/// Lines are broken after spaces in the lyrics, so each chord stays
+/// with the start of its lyrics.  Continuation lines are `indent`
+/// narrower, and (like any line from `Segment::parse_line`) start
+/// with a segment without a chord.
+/// A word that is wider than the line is not broken.
+fn wrap_line(
+    segments: Vec<Segment>,
+    width: f32,
+    indent: f32,
+    measure: impl Fn(&[Segment]) -> f32,
+) -> Vec<Vec<Segment>> {
+    let mut lines = vec![];
+    let mut rest = segments;
+    loop {
+        let width = if lines.is_empty() {
+            width
+        } else {
+            width - indent
+        };
+        if measure(&rest) <= width {
+            break;
+        }
+        let breaks = break_points(&rest);
+        let fits = breaks
+            .iter()
+            .rev()
+            .find(|(i, at)| measure(&split_line(&rest, *i, *at).0) <= width);
+        let Some(&(i, at)) = fits.or(breaks.first()) else {
+            break;
+        };
+        let (head, tail) = split_line(&rest, i, at);
+        lines.push(head);
+        rest = tail;
+    }
+    lines.push(rest);
+    lines
+}
+
+/// The positions (segment index and byte offset in its lyrics) where
+/// a line can be broken, i.e. the start of each word but the first.
+fn break_points(segments: &[Segment]) -> Vec<(usize, usize)> {
+    let last = segments.len() - 1;
+    let mut breaks = vec![];
+    let mut has_text = false;
+    for (i, segment) in segments.iter().enumerate() {
+        has_text |= segment.chord.is_some();
+        let lyrics = &segment.lyrics;
+        for (at, ch) in lyrics.char_indices() {
+            if ch != ' ' && lyrics[..at].ends_with(' ') && has_text {
+                breaks.push((i, at));
+            }
+            has_text |= ch != ' ';
+        }
+        if i < last && lyrics.ends_with(' ') && has_text {
+            breaks.push((i, lyrics.len()));
+        }
+    }
+    breaks
+}
+
+/// Split a line at a break point from `break_points`.
+fn split_line(
+    segments: &[Segment],
+    i: usize,
+    at: usize,
+) -> (Vec<Segment>, Vec<Segment>) {
+    let (lyrics, rest) = segments[i].lyrics.split_at(at);
+    let mut head = segments[..i].to_vec();
+    head.push(Segment {
+        chord: segments[i].chord.clone(),
+        lyrics: lyrics.trim_end().to_string(),
+    });
+    let mut tail = vec![Segment {
+        chord: None,
+        lyrics: rest.to_string(),
+    }];
+    tail.extend_from_slice(&segments[i + 1..]);
+    (head, tail)
+}
+
+#[test]
+fn test_wrap_line() {
+    let measure = |segments: &[Segment]| {
+        segments.iter().map(|s| s.lyrics.len()).sum::<usize>() as f32
+    };
+    let wrap = |line: &str, width: f32| {
+        wrap_line(Segment::parse_line(line), width, 2., measure)
+            .iter()
+            .map(|segments| {
+                segments
+                    .iter()
+                    .map(|s| match &s.chord {
+                        Some(chord) => format!("[{chord}]{}", s.lyrics),
+                        None => s.lyrics.clone(),
+                    })
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(wrap("[G]Hello [C]world", 20.), ["[G]Hello [C]world"]);
+    assert_eq!(wrap("[G]Hello [C]world", 8.), ["[G]Hello", "[C]world"]);
+    assert_eq!(
+        wrap("Yester[G]day, all my [F#m]troubles seemed", 16.),
+        ["Yester[G]day, all", "my [F#m]troubles", "seemed"],
+    );
+    assert_eq!(
+        wrap("[C]Supercalifragilistic", 4.),
+        ["[C]Supercalifragilistic"],
+    );
+    assert_eq!(wrap("  Indented [G]line", 8.), ["  Indented", "[G]line"]);
+}
+
+/// The indentation of the lines of a section.
+fn section_indent(kind: &SectionKind, base_size: f32) -> f32 {
+    match kind {
+        SectionKind::Bridge => 2. * base_size,
+        _ => 0.,
+    }
+}
+
+/// The height of a token, as rendered by `render_token` at `left`.
+///
+/// Column and page breaks have no height, they are handled by
+/// `render_token`.
+fn token_height(
+    token: &Token,
+    left: f32,
+    song: &SongState,
+    base_size: f32,
+) -> f32 {
+    let lines_height = |lines: &[Token], left| {
+        lines
+            .iter()
+            .map(|line| token_height(line, left, song, base_size))
+            .sum::<f32>()
+    };
+    match &token.expr {
+        ChordFileExpression::Comment { .. }
+        | ChordFileExpression::Unknown { .. } => base_size,
+        ChordFileExpression::Chorus { lines, .. } => {
+            lines_height(lines, left + 10.0) + 4.0
+        }
+        ChordFileExpression::ChorusRecall { .. } => 1.4 * base_size + 4.0,
+        ChordFileExpression::Section { kind, lines, .. } => {
+            lines_height(lines, left + section_indent(kind, base_size))
+        }
+        ChordFileExpression::Tab { lines } => {
+            lines.len() as f32 * base_size / 1.2
+        }
+        ChordFileExpression::Form { form, .. } => {
+            let leading = 0.82 * base_size * 1.2;
+            base_size + (form.len() as f32 + 0.5) * leading
+        }
+        ChordFileExpression::Line { segments } => {
+            wrap_segments(segments.clone(), left, song, base_size)
+                .iter()
+                .map(|segments| line_height(segments, base_size))
+                .sum()
+        }
+        _ => 0.,
+    }
+}
+
+/// Split a chorus or section that is higher than `space`, so that
+/// the first part fits.
+///
+/// Returns the first part and the rest, if the token is split.
+/// The first part has at least one line.
+fn split_section(
+    token: Token,
+    space: f32,
+    left: f32,
+    song: &SongState,
+    base_size: f32,
+) -> (Token, Option<Token>) {
+    let fitting = |lines: &[Token], left, space| {
+        let mut height = 0.;
+        let n = lines
+            .iter()
+            .take_while(|line| {
+                height += token_height(line, left, song, base_size);
+                height <= space
+            })
+            .count();
+        n.max(1)
+    };
+    let Token {
+        pos,
+        expr,
+        selector,
+    } = token;
+    let token = |expr| Token {
+        pos,
+        expr,
+        selector: selector.clone(),
+    };
+    match expr {
+        ChordFileExpression::Chorus { label, mut lines } => {
+            let n = fitting(&lines, left + 10.0, space - 4.0);
+            if n >= lines.len() {
+                return (
+                    token(ChordFileExpression::Chorus { label, lines }),
+                    None,
+                );
+            }
+            let rest = lines.split_off(n);
+            (
+                token(ChordFileExpression::Chorus { label, lines }),
+                Some(token(ChordFileExpression::Chorus {
+                    label: None,
+                    lines: rest,
+                })),
+            )
+        }
+        ChordFileExpression::Section {
+            kind,
+            label,
+            mut lines,
+        } => {
+            let indent = section_indent(&kind, base_size);
+            let n = fitting(&lines, left + indent, space);
+            if n >= lines.len() {
+                let expr = ChordFileExpression::Section { kind, label, lines };
+                return (token(expr), None);
+            }
+            let rest = lines.split_off(n);
+            (
+                token(ChordFileExpression::Section {
+                    kind: kind.clone(),
+                    label,
+                    lines,
+                }),
+                Some(token(ChordFileExpression::Section {
+                    kind,
+                    label: None,
+                    lines: rest,
+                })),
+            )
+        }
+        expr => (token(expr), None),
+    }
+}
+
+#[test]
+fn test_split_section() {
+    let options = RenderOptions {
+        base_size: 10.,
+        ..RenderOptions::default()
+    };
+    let source = "{title: Test}\n{soc: Chorus}\nla\nla\n[G]la\nla\n{eoc}\n";
+    let body = song(source).body;
+    let diagnostics = Diagnostics::new("test");
+    let song = SongState::new(&options, &diagnostics);
+    let chorus = body[0].clone();
+    let height = token_height(&chorus, 0., &song, 10.);
+    assert!((height - (3. * 11. + 1.1 * 18.2 + 4.)).abs() < 0.001);
+    assert_eq!(split_section(chorus.clone(), 60., 0., &song, 10.).1, None);
+    let (head, rest) = split_section(chorus, 30., 0., &song, 10.);
+    let ChordFileExpression::Chorus { label, lines } = head.expr else {
+        panic!("Expected a chorus");
+    };
+    assert_eq!((label.as_deref(), lines.len()), (Some("Chorus"), 2));
+    let rest = rest.map(|rest| rest.expr);
+    let Some(ChordFileExpression::Chorus { label, lines }) = rest else {
+        panic!("Expected the rest of the chorus");
+    };
+    assert_eq!((label, lines.len()), (None, 2));
+}
+
+/// Render the lines of a chorus or section.
+///
+/// Returns the baseline of the first line (for placing a label) and
+/// the y position after the last line.
+fn render_lines(
+    lines: Vec<Token>,
+    y: f32,
+    left: f32,
+    c: &mut Canvas<'_>,
+    song: &mut SongState,
+    base_size: f32,
+    box_w: &mut f32,
+) -> io::Result<(f32, f32)> {
+    let mut first = None;
+    let mut y = y;
+    for line in lines {
+        y = render_token(line, y, left, c, song, base_size, box_w)?;
+        first.get_or_insert(y);
+    }
+    Ok((first.unwrap_or(y), y))
+}
+
+/// Draw the vertical bar that marks a chorus, from `top` to `bottom`.
+fn chorus_bar(
+    c: &mut Canvas<'_>,
+    left: f32,
+    top: f32,
+    bottom: f32,
+) -> io::Result<()> {
+    c.set_line_width(0.5)?;
+    c.line(left - 6.0, top, left - 6.0, bottom)?;
+    c.stroke()
+}
+
+/// Render a section label in the margin, right-aligned at `right`.
+fn render_label(
+    c: &mut Canvas<'_>,
+    label: &str,
+    right: f32,
+    y: f32,
+    base_size: f32,
+) -> io::Result<()> {
+    c.right_text(right, y, BuiltinFont::Times_Italic, 0.75 * base_size, label)
+}
+
+fn write_chord(t: &mut TextObject, chord: &str) -> io::Result<()> {
+    match chord.parse::<Chord>() {
+        Ok(chord) => {
+            t.show(&format!("{}{}", chord.root, chord.quality))?;
+            let suffix = chord.suffix();
+            if !suffix.is_empty() {
+                t.set_rise(-1.8)?;
+                // TODO: Also slightly smaller?
+                t.show(&suffix)?;
+                t.set_rise(0.)?;
+            }
+            if let Some(bass) = chord.bass {
+                t.show(&format!("/{bass}"))?;
+            }
+        }
+        Err(_) => t.show(chord)?,
+    }
+    t.show(" ")
+}
@@ -0,0 +1,298 @@
+use crate::chord::transpose;
+use crate::chords::transpose_shape;
+use crate::diagnostics::{Code, Diagnostics, Pos};
+use crate::metadata::Metadata;
+use crate::parser::{ChoproParser, ChordFileExpression, Token};
+use std::io;
+use std::path::Path;
+
+/// A parsed song.
+///
+/// The header expressions of the song (title, subtitles, metadata
+/// and chord definitions) are collected in fields of their own, and
+/// everything else is kept in order in the `body`.
+#[derive(Clone, Debug, Default)]
+pub struct Song {
+    /// The position of the first expression of the song.
+    pub pos: Pos,
+    pub title: Option<String>,
+    pub subtitles: Vec<String>,
+    pub meta: Metadata,
+    pub chord_defs: Vec<ChordDef>,
+    /// The contents of the song: lines, comments, choruses, etc.
+    pub body: Vec<Token>,
+}
+
+/// A chord definition from a `{define}` directive.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChordDef {
+    pub pos: Pos,
+    pub name: String,
+    /// The base fret, followed by the fret for each string.
+    pub def: Vec<i8>,
+    /// The finger for each string, or empty.
+    pub fingers: Vec<u8>,
+}
+
+impl Song {
+    /// Parse all songs from a chopro source.
+    ///
+    /// See `ChoproParser::new` for the arguments.
+    pub fn parse<R: io::Read>(
+        source: R,
+        path: &Path,
+        diagnostics: &Diagnostics,
+        selectors: &[String],
+    ) -> Vec<Song> {
+        Song::collect(
+            ChoproParser::new(source, path, diagnostics, selectors),
+            diagnostics,
+        )
+    }
+
+    /// Collect songs from tokens.
+    ///
+    /// A `{new_song}` directive separates songs.  Songs without any
+    /// expressions are ignored.
+    /// The chords following a `{transpose}` directive are transposed.
+    /// A second `{title}` in a song is reported to `diagnostics`.
+    pub fn collect(
+        tokens: impl IntoIterator<Item = Token>,
+        diagnostics: &Diagnostics,
+    ) -> Vec<Song> {
+        let mut songs = vec![];
+        let mut song: Option<Song> = None;
+        let mut semitones = 0;
+        for mut token in tokens {
+            match token.expr {
+                ChordFileExpression::NewSong => {
+                    songs.extend(song.take());
+                    semitones = 0;
+                    continue;
+                }
+                ChordFileExpression::Transpose { semitones: s } => {
+                    semitones = s;
+                    continue;
+                }
+                _ => (),
+            }
+            transpose_expr(&mut token.expr, semitones);
+            let song = song.get_or_insert_with(|| Song {
+                pos: token.pos,
+                ..Default::default()
+            });
+            if let Some(token) = song.take_header(token, diagnostics) {
+                song.body.push(token);
+            }
+        }
+        songs.extend(song);
+        songs
+    }
+
+    /// Transpose all chords of the song (and its key) by `semitones`.
+    ///
+    /// Chord definitions are renamed and moved on the fretboard.
+    pub fn transpose(&mut self, semitones: i8) {
+        if semitones == 0 {
+            return;
+        }
+        for token in &mut self.body {
+            transpose_expr(&mut token.expr, semitones);
+        }
+        for def in &mut self.chord_defs {
+            let ChordDef {
+                name, def, fingers, ..
+            } = def;
+            transpose_def(name, def, fingers, semitones);
+        }
+        self.meta.transform("key", |key| transpose(key, semitones));
+    }
+
+    /// Store a header expression in this song.
+    ///
+    /// Returns the token if it is not a header expression.  Header
+    /// expressions inside choruses and sections are also taken, since
+    /// they apply to the whole song.
+    fn take_header(
+        &mut self,
+        token: Token,
+        diagnostics: &Diagnostics,
+    ) -> Option<Token> {
+        let Token {
+            pos,
+            expr,
+            selector,
+        } = token;
+        let expr = match expr {
+            ChordFileExpression::Title { s } => {
+                if self.title.is_some() {
+                    diagnostics.warning(
+                        pos,
+                        Code::DuplicateTitle,
+                        format!(
+                            "Ignoring title {s:?}, the song already has a \
+                             title.  Use {{new_song}} to start a new song"
+                        ),
+                    );
+                } else {
+                    self.title = Some(s);
+                }
+                return None;
+            }
+            ChordFileExpression::SubTitle { s } => {
+                self.subtitles.push(s);
+                return None;
+            }
+            ChordFileExpression::Meta { name, value } => {
+                self.meta.add(&name, &value);
+                return None;
+            }
+            ChordFileExpression::ChordDef { name, def, fingers } => {
+                self.chord_defs.push(ChordDef {
+                    pos,
+                    name,
+                    def,
+                    fingers,
+                });
+                return None;
+            }
+            ChordFileExpression::Chorus { label, lines } => {
+                ChordFileExpression::Chorus {
+                    label,
+                    lines: self.take_headers(lines, diagnostics),
+                }
+            }
+            ChordFileExpression::Section { kind, label, lines } => {
+                ChordFileExpression::Section {
+                    kind,
+                    label,
+                    lines: self.take_headers(lines, diagnostics),
+                }
+            }
+            expr => expr,
+        };
+        Some(Token {
+            pos,
+            expr,
+            selector,
+        })
+    }
+
+    fn take_headers(
+        &mut self,
+        lines: Vec<Token>,
+        diagnostics: &Diagnostics,
+    ) -> Vec<Token> {
+        lines
+            .into_iter()
+            .filter_map(|line| self.take_header(line, diagnostics))
+            .collect()
+    }
+}
+
+/// Transpose a chord definition by `semitones`.
+///
+/// The fingers are cleared if the shape no longer has open strings
+/// where the original had, since they would be incomplete.
+fn transpose_def(
+    name: &mut String,
+    def: &mut Vec<i8>,
+    fingers: &mut Vec<u8>,
+    semitones: i8,
+) {
+    *name = transpose(name, semitones);
+    let moved = transpose_shape(def, semitones);
+    if def[1..].contains(&0) && !moved[1..].contains(&0) {
+        fingers.clear();
+    }
+    *def = moved;
+}
+
+/// Transpose the chords of an expression by `semitones`.
+fn transpose_expr(expr: &mut ChordFileExpression, semitones: i8) {
+    if semitones == 0 {
+        return;
+    }
+    match expr {
+        ChordFileExpression::Meta { name, value } if name == "key" => {
+            *value = transpose(value, semitones);
+        }
+        ChordFileExpression::ChordDef { name, def, fingers } => {
+            transpose_def(name, def, fingers, semitones);
+        }
+        ChordFileExpression::Chorus { lines, .. }
+        | ChordFileExpression::Section { lines, .. } => {
+            for line in lines {
+                transpose_expr(&mut line.expr, semitones);
+            }
+        }
+        ChordFileExpression::Form { keys, .. } => {
+            for key in keys {
+                *key = transpose(key, semitones);
+            }
+        }
+        ChordFileExpression::Line { segments } => {
+            for chord in segments.iter_mut().filter_map(|s| s.chord.as_mut()) {
+                *chord = transpose(chord, semitones);
+            }
+        }
+        _ => (),
+    }
+}
+
+#[test]
+fn test_collect_songs() {
+    let source = "{title: One}\n{st: Sub}\n{key: G}\n\
+                  {soc}\n{define: X frets 0 0 0 0 0 0}\n[G]la\n{eoc}\n\
+                  {new_song}\n{new_song}\n\
+                  {t: Two}\nHello\n";
+    let diagnostics = Diagnostics::new("test");
+    let songs =
+        Song::parse(source.as_bytes(), Path::new("test"), &diagnostics, &[]);
+    assert_eq!(songs.len(), 2);
+    assert_eq!(songs[0].title.as_deref(), Some("One"));
+    assert_eq!(songs[0].subtitles, vec!["Sub".to_string()]);
+    assert_eq!(songs[0].meta.get("key").as_deref(), Some("G"));
+    assert_eq!(songs[0].chord_defs[0].name, "X");
+    assert_eq!(songs[0].body.len(), 1);
+    match &songs[0].body[0].expr {
+        ChordFileExpression::Chorus { lines, .. } => assert_eq!(lines.len(), 1),
+        _ => panic!("Expected a chorus"),
+    }
+    assert_eq!(songs[1].title.as_deref(), Some("Two"));
+    assert_eq!(songs[1].pos, Pos::new(10, 1));
+    assert_eq!(diagnostics.n_warnings(), 0);
+
+    let source = "{title: One}\nla\n{title: Two}\nla\n";
+    let songs =
+        Song::parse(source.as_bytes(), Path::new("test"), &diagnostics, &[]);
+    assert_eq!(songs.len(), 1);
+    assert_eq!(songs[0].title.as_deref(), Some("One"));
+    assert_eq!(diagnostics.n_warnings(), 1);
+}
+
+#[test]
+fn test_transpose_song() {
+    let source = "{key: G}\n{define: G frets 3 2 0 0 0 3}\n[G]la\n\
+                  {transpose: 2}\n{soc}\n[C/G]la\n{eoc}\n";
+    let diagnostics = Diagnostics::new("test");
+    let mut songs =
+        Song::parse(source.as_bytes(), Path::new("test"), &diagnostics, &[]);
+    let song = &mut songs[0];
+    song.transpose(-1);
+    assert_eq!(song.meta.get("key").as_deref(), Some("F#"));
+    assert_eq!(song.chord_defs[0].name, "F#");
+    fn chords(token: &Token) -> Vec<String> {
+        match &token.expr {
+            ChordFileExpression::Line { segments } => {
+                segments.iter().filter_map(|s| s.chord.clone()).collect()
+            }
+            ChordFileExpression::Chorus { lines, .. } => {
+                lines.iter().flat_map(chords).collect()
+            }
+            _ => vec![],
+        }
+    }
+    assert_eq!(chords(&song.body[0]), vec!["F#".to_string()]);
+    assert_eq!(chords(&song.body[1]), vec!["C#/G#".to_string()]);
+}
@@ -0,0 +1,177 @@
+//! Find fingerings for chords on a fretted instrument.
+use crate::chord::Chord;
+
+/// The number of frets a hand is assumed to cover.
+const SPAN: i8 = 4;
+
+/// The highest fret where a voicing may start.
+const MAX_POSITION: i8 = 12;
+
+/// Find playable voicings for a chord, best first.
+///
+/// The `tuning` is the open note of each string, as semitones above A
+/// (as given by `Note::semitone`), from the lowest string.
+/// Each voicing is a base fret followed by a fret for each string,
+/// in the same form as chord definitions: 0 for an open string, -1
+/// for a string not played, and frets relative to the base fret if
+/// it is more than 1.
+pub fn voicings(chord: &Chord, tuning: &[i8]) -> Vec<Vec<i8>> {
+    let root = chord.root.semitone();
+    let tones = chord
+        .intervals()
+        .into_iter()
+        .map(|(i, essential)| ((root + i).rem_euclid(12), essential))
+        .collect::<Vec<_>>();
+    let search = Search {
+        tones: &tones,
+        root,
+        bass: chord.bass.map(|bass| bass.semitone()),
+        tuning,
+    };
+    let mut found: Vec<(i32, Vec<i8>)> = vec![];
+    let mut frets = Vec::with_capacity(tuning.len());
+    for position in 1..=MAX_POSITION {
+        search.find(position, &mut frets, &mut found);
+    }
+    found.sort();
+    found.dedup_by(|a, b| a.1 == b.1);
+    found.into_iter().map(|(_, frets)| to_def(&frets)).collect()
+}
+
+struct Search<'a> {
+    /// The pitch class of each chord tone, and if it is essential.
+    tones: &'a [(i8, bool)],
+    root: i8,
+    bass: Option<i8>,
+    tuning: &'a [i8],
+}
+
+impl Search<'_> {
+    fn allowed(&self, note: i8) -> bool {
+        self.tones.iter().any(|(t, _)| *t == note) || self.bass == Some(note)
+    }
+
+    /// Recursively try all frets within the span from `position` for
+    /// the remaining strings, adding complete voicings to `found`.
+    fn find(
+        &self,
+        position: i8,
+        frets: &mut Vec<i8>,
+        found: &mut Vec<(i32, Vec<i8>)>,
+    ) {
+        let Some(&open) = self.tuning.get(frets.len()) else {
+            found.extend(self.score(frets).map(|score| (score, frets.clone())));
+            return;
+        };
+        let candidates = [-1, 0].into_iter().chain(position..position + SPAN);
+        for fret in candidates {
+            if fret == -1 || self.allowed((open + fret).rem_euclid(12)) {
+                frets.push(fret);
+                self.find(position, frets, found);
+                frets.pop();
+            }
+        }
+    }
+
+    /// Score a complete voicing, lower is better.
+    ///
+    /// Returns None if the voicing does not contain the essential
+    /// tones or is not playable.
+    fn score(&self, frets: &[i8]) -> Option<i32> {
+        let notes = frets
+            .iter()
+            .zip(self.tuning)
+            .filter(|(fret, _)| **fret >= 0)
+            .map(|(fret, open)| (open + fret).rem_euclid(12))
+            .collect::<Vec<_>>();
+        if notes.len() < frets.len().min(3) {
+            return None;
+        }
+        if self
+            .tones
+            .iter()
+            .any(|(tone, essential)| *essential && !notes.contains(tone))
+        {
+            return None;
+        }
+        let lowest = notes[0];
+        if self.bass.is_some_and(|bass| bass != lowest) {
+            return None;
+        }
+        let fingers = fingers(frets)?;
+        let fretted = frets.iter().filter(|f| **f > 0);
+        let min = fretted.clone().min().copied().unwrap_or(0);
+        let max = fretted.max().copied().unwrap_or(0);
+        let muted = frets.iter().filter(|f| **f < 0).count();
+        let first = frets.iter().position(|f| *f >= 0).unwrap_or(0);
+        let last = frets.iter().rposition(|f| *f >= 0).unwrap_or(0);
+        let inner_muted =
+            frets[first..=last].iter().filter(|f| **f < 0).count();
+        let missing = self
+            .tones
+            .iter()
+            .filter(|(tone, _)| !notes.contains(tone))
+            .count();
+        let root_bass = if self.bass.is_some() || lowest == self.root {
+            0
+        } else {
+            3
+        };
+        Some(
+            3 * muted as i32
+                + 4 * inner_muted as i32
+                + 2 * missing as i32
+                + i32::from(fingers)
+                + i32::from(max - min)
+                + i32::from(min)
+                + root_bass,
+        )
+    }
+}
+
+/// The number of fingers needed for a voicing, or None if it is not
+/// playable.
+///
+/// The lowest fret may be played as a barre, if there are no open
+/// strings above its first use.
+fn fingers(frets: &[i8]) -> Option<u8> {
+    let fretted = frets.iter().filter(|f| **f > 0).count() as u8;
+    let Some(&min) = frets.iter().filter(|f| **f > 0).min() else {
+        return Some(0);
+    };
+    let first = frets.iter().position(|f| *f == min).unwrap_or(0);
+    let at_min = frets.iter().filter(|f| **f == min).count() as u8;
+    let barre =
+        at_min > 1 && frets[first..].iter().all(|f| *f == -1 || *f >= min);
+    let fingers = if barre { fretted - at_min + 1 } else { fretted };
+    (fingers <= 4).then_some(fingers)
+}
+
+/// Convert absolute frets to a chord definition.
+fn to_def(frets: &[i8]) -> Vec<i8> {
+    let max = frets.iter().copied().max().unwrap_or(0);
+    let min = frets.iter().copied().filter(|f| *f > 0).min().unwrap_or(1);
+    if max <= SPAN {
+        [1].iter().chain(frets).copied().collect()
+    } else {
+        let relative =
+            frets.iter().map(|&f| if f > 0 { f - min + 1 } else { f });
+        [min].into_iter().chain(relative).collect()
+    }
+}
+
+#[test]
+fn test_voicings() {
+    let guitar = [7, 0, 5, 10, 2, 7];
+    let best = |name: &str| {
+        let chord = name.parse::<Chord>().unwrap();
+        voicings(&chord, &guitar).into_iter().next()
+    };
+    assert_eq!(best("C"), Some(vec![1, -1, 3, 2, 0, 1, 0]));
+    assert_eq!(best("E"), Some(vec![1, 0, 2, 2, 1, 0, 0]));
+    assert_eq!(best("Am"), Some(vec![1, -1, 0, 2, 2, 1, 0]));
+    assert_eq!(best("D/F#"), Some(vec![1, 2, 0, 0, 2, 3, 2]));
+    let mandolin = [10, 5, 0, 7];
+    let chord = "G".parse::<Chord>().unwrap();
+    assert_eq!(voicings(&chord, &mandolin)[0], vec![1, 0, 0, 2, 3]);
+}